@@ -0,0 +1,202 @@
+//! Throughput of the update path, from a local publisher through the
+//! subscriber, `updates_loop` and `refresh_data` to a simulated RTD host.
+//!
+//! cargo bench --bench updates
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{Host, Netidx};
+use netidx::{publisher::Val, subscriber::Value};
+use netidx_excel::{cell::Cell, server::TopicId};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+struct Report {
+    name: &'static str,
+    elapsed: Duration,
+    /// updates sent by the publisher
+    published: usize,
+    /// cell updates handed to the host by refresh_data
+    delivered: usize,
+    batches: Vec<usize>,
+    latencies: Vec<Duration>,
+}
+
+fn percentile<T: Copy + Ord>(v: &mut [T], p: f64) -> Option<T> {
+    v.sort();
+    let i = ((v.len() as f64 - 1.) * p).round() as usize;
+    v.get(i).copied()
+}
+
+impl Report {
+    fn new(name: &'static str, published: usize, elapsed: Duration, host: &Host) -> Self {
+        Report {
+            name,
+            elapsed,
+            published,
+            delivered: host.batches.iter().sum(),
+            batches: host.batches.clone(),
+            latencies: host.latencies.clone(),
+        }
+    }
+
+    fn print(mut self) {
+        let secs = self.elapsed.as_secs_f64();
+        println!("{}:", self.name);
+        println!("  elapsed           {:.3}s", secs);
+        println!(
+            "  published         {} ({:.0}/s)",
+            self.published,
+            self.published as f64 / secs
+        );
+        println!(
+            "  delivered         {} ({:.0}/s)",
+            self.delivered,
+            self.delivered as f64 / secs
+        );
+        println!(
+            "  batches           {} (mean {:.1}, p50 {:?}, p99 {:?}, max {:?})",
+            self.batches.len(),
+            self.delivered as f64 / self.batches.len().max(1) as f64,
+            percentile(&mut self.batches, 0.5).unwrap_or(0),
+            percentile(&mut self.batches, 0.99).unwrap_or(0),
+            self.batches.iter().max().copied().unwrap_or(0),
+        );
+        println!(
+            "  refresh latency   p50 {:?}, p99 {:?}, max {:?}",
+            percentile(&mut self.latencies, 0.5).unwrap_or_default(),
+            percentile(&mut self.latencies, 0.99).unwrap_or_default(),
+            self.latencies.iter().max().copied().unwrap_or_default(),
+        );
+    }
+}
+
+/// publish `rounds` updates to every val, as fast as the publisher will
+/// take them
+fn pump(netidx: &Netidx, vals: Vec<Val>, rounds: i64) {
+    let publisher = netidx.publisher().clone();
+    netidx.runtime.spawn(async move {
+        for i in 1..=rounds {
+            let mut batch = publisher.start_batch();
+            for val in &vals {
+                val.update(&mut batch, Value::I64(i));
+            }
+            batch.commit(None).await
+        }
+    });
+}
+
+/// refresh until every topic in `tids` shows `last`
+fn drain(host: &mut Host, tids: &[TopicId], last: i64) {
+    let mut waiting = tids.iter().copied().collect::<HashSet<_>>();
+    let last = Cell::I64(last);
+    while !waiting.is_empty() {
+        for (tid, cell) in host.refresh() {
+            if cell == last {
+                waiting.remove(&tid);
+            }
+        }
+    }
+}
+
+fn start(
+    netidx: &Netidx,
+    host: &mut Host,
+    paths: usize,
+    topics_per_path: usize,
+) -> (Vec<Val>, Vec<TopicId>) {
+    let mut vals = vec![];
+    let mut tids = vec![];
+    for i in 0..paths {
+        let path = format!("/bench/{}", i);
+        vals.push(netidx.publish(&path, Value::I64(0)));
+        for _ in 0..topics_per_path {
+            tids.push(host.connect(&[&path]));
+        }
+    }
+    drain(host, &tids, 0);
+    host.batches.clear();
+    host.latencies.clear();
+    (vals, tids)
+}
+
+fn fan_out(topics: usize, rounds: i64) {
+    let netidx = Netidx::start();
+    let mut host = Host::new(&netidx);
+    let (vals, tids) = start(&netidx, &mut host, 1, topics);
+    let now = Instant::now();
+    pump(&netidx, vals, rounds);
+    drain(&mut host, &tids, rounds);
+    Report::new("one path fanned out", rounds as usize, now.elapsed(), &host).print();
+    host.terminate();
+}
+
+fn many_paths(paths: usize, rounds: i64) {
+    let netidx = Netidx::start();
+    let mut host = Host::new(&netidx);
+    let (vals, tids) = start(&netidx, &mut host, paths, 1);
+    let now = Instant::now();
+    pump(&netidx, vals, rounds);
+    drain(&mut host, &tids, rounds);
+    Report::new("many paths", paths * rounds as usize, now.elapsed(), &host).print();
+    host.terminate();
+}
+
+fn churn(paths: usize, topics: usize, iterations: usize) {
+    let netidx = Netidx::start();
+    let mut host = Host::new(&netidx);
+    let vals = (0..paths)
+        .map(|i| netidx.publish(&format!("/bench/{}", i), Value::I64(0)))
+        .collect::<Vec<_>>();
+    let publisher = netidx.publisher().clone();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_ = stop.clone();
+    let published = netidx.runtime.spawn(async move {
+        let mut n = 0;
+        let mut i = 0;
+        while !stop_.load(Ordering::Relaxed) {
+            i += 1;
+            let mut batch = publisher.start_batch();
+            for val in &vals {
+                val.update(&mut batch, Value::I64(i));
+            }
+            batch.commit(None).await;
+            n += vals.len();
+        }
+        n
+    });
+    let now = Instant::now();
+    for i in 0..iterations {
+        let tids = (0..topics)
+            .map(|j| host.connect(&[&format!("/bench/{}", (i + j) % paths)]))
+            .collect::<Vec<_>>();
+        host.refresh();
+        for tid in tids {
+            host.disconnect(tid);
+        }
+    }
+    let elapsed = now.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    let published = netidx.runtime.block_on(published).unwrap();
+    let connects = iterations * topics;
+    Report::new("connect/disconnect churn", published, elapsed, &host).print();
+    println!(
+        "  connect+disconnect {} ({:.0}/s)",
+        connects,
+        connects as f64 / elapsed.as_secs_f64()
+    );
+    assert_eq!(host.server.stats().topics, 0);
+    host.terminate();
+}
+
+fn main() {
+    fan_out(10_000, 200);
+    many_paths(10_000, 200);
+    churn(1_000, 100, 1_000);
+}
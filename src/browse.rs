@@ -0,0 +1,167 @@
+use crate::server::TopicId;
+use anyhow::Result;
+use fxhash::FxHashMap;
+use netidx::{
+    chars::Chars,
+    glob::{Glob, GlobSet},
+    path::Path,
+    resolver_client::ResolverRead,
+    subscriber::Value,
+};
+use std::{collections::hash_map::Entry, sync::Arc, time::Duration};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Query {
+    List(Path),
+    Glob(Chars),
+    Rows(Path),
+    Columns(Path),
+}
+
+type Listing = Arc<Result<Vec<String>, String>>;
+
+/// how often a query is asked again, so paths that were published or
+/// went away since are shown
+pub(crate) const INTERVAL: Duration = Duration::from_secs(10);
+
+/// a query, and the profile whose resolver answers it
+type Key = (Option<String>, Query);
+
+struct Cached {
+    refs: usize,
+    listing: Option<Listing>,
+    /// identifies the task asking the resolver
+    token: Arc<()>,
+}
+
+/// Resolver queries made from the workbook. Topics asking the same
+/// question share one query, which is asked again every `INTERVAL` for
+/// as long as any topic refers to it.
+pub(crate) struct Browse {
+    topics: FxHashMap<TopicId, (Key, Option<usize>)>,
+    cache: FxHashMap<Key, Cached>,
+}
+
+fn select(listing: &Listing, idx: Option<usize>) -> Value {
+    match &**listing {
+        Err(e) => Value::Error(Chars::from(e.clone())),
+        Ok(l) => match idx {
+            None => Value::I64(l.len() as i64),
+            Some(i) => match l.get(i) {
+                None => Value::Null,
+                Some(p) => Value::String(Chars::from(p.clone())),
+            },
+        },
+    }
+}
+
+fn name(p: &Path) -> String {
+    String::from(Path::basename(p).unwrap_or(&**p))
+}
+
+pub(crate) async fn fetch(resolver: ResolverRead, query: Query) -> Result<Vec<String>> {
+    let mut res = match query {
+        Query::List(path) => {
+            resolver.list(path).await?.iter().map(|p| String::from(&**p)).collect()
+        }
+        Query::Glob(pat) => {
+            let globs = GlobSet::new(false, [Glob::new(pat)?])?;
+            let mut res = vec![];
+            for paths in resolver.list_matching(&globs).await?.iter() {
+                res.extend(paths.iter().map(|p| String::from(&**p)));
+            }
+            res
+        }
+        Query::Rows(path) => resolver.table(path).await?.rows.iter().map(name).collect(),
+        Query::Columns(path) => {
+            resolver.table(path).await?.cols.iter().map(|(p, _)| name(p)).collect()
+        }
+    };
+    res.sort();
+    res.dedup();
+    Ok(res)
+}
+
+impl Browse {
+    pub(crate) fn new() -> Self {
+        Browse { topics: FxHashMap::default(), cache: FxHashMap::default() }
+    }
+
+    /// register `tid`. If the query needs to be fetched the token to
+    /// hand to `ready` with the result is returned.
+    pub(crate) fn add(
+        &mut self,
+        tid: TopicId,
+        profile: Option<String>,
+        query: Query,
+        idx: Option<usize>,
+    ) -> Option<Arc<()>> {
+        self.remove(tid);
+        let key = (profile, query);
+        self.topics.insert(tid, (key.clone(), idx));
+        match self.cache.entry(key) {
+            Entry::Occupied(mut e) => {
+                e.get_mut().refs += 1;
+                None
+            }
+            Entry::Vacant(e) => {
+                let token = Arc::new(());
+                e.insert(Cached { refs: 1, listing: None, token: token.clone() });
+                Some(token)
+            }
+        }
+    }
+
+    /// the value of `tid`, if the result of its query is available
+    pub(crate) fn value(&self, tid: TopicId) -> Option<Value> {
+        let (key, idx) = self.topics.get(&tid)?;
+        let listing = self.cache.get(key)?.listing.as_ref()?;
+        Some(select(listing, *idx))
+    }
+
+    /// store the result of `query`, and return the values of all the
+    /// topics showing it if it changed. Returns None if nothing refers
+    /// to the query anymore, and it should not be asked again.
+    pub(crate) fn ready(
+        &mut self,
+        token: &Arc<()>,
+        profile: Option<String>,
+        query: Query,
+        listing: Result<Vec<String>, String>,
+    ) -> Option<Vec<(TopicId, Value)>> {
+        let key = (profile, query);
+        match self.cache.get_mut(&key) {
+            Some(cached) if Arc::ptr_eq(&cached.token, token) => {
+                if cached.listing.as_deref() == Some(&listing) {
+                    return Some(vec![]);
+                }
+                let listing = Arc::new(listing);
+                cached.listing = Some(listing.clone());
+                self.topics
+                    .iter()
+                    .filter(|(_, (k, _))| k == &key)
+                    .map(|(tid, (_, idx))| (*tid, select(&listing, *idx)))
+                    .collect()
+            }
+            // a query from before the topics let go of it, or from a
+            // server that has been replaced
+            Some(_) | None => None,
+        }
+    }
+
+    pub(crate) fn remove(&mut self, tid: TopicId) {
+        if let Some((key, _)) = self.topics.remove(&tid) {
+            if let Entry::Occupied(mut e) = self.cache.entry(key) {
+                e.get_mut().refs -= 1;
+                if e.get().refs == 0 {
+                    e.remove();
+                }
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.topics.clear();
+        self.cache.clear();
+    }
+}
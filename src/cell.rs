@@ -0,0 +1,294 @@
+use crate::options::{DateTimeFormat, DecimalFormat, DurationFormat, Errors, Options};
+use chrono::{prelude::*, Duration as ChronoDuration};
+use netidx::{
+    chars::Chars,
+    subscriber::{Event, Value},
+};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use std::time::Duration;
+
+/// Excel's error values, as they appear in the scode of a VT_ERROR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExcelError {
+    /// #NULL!
+    Null = 0x800A07D0,
+    /// #DIV/0!
+    Div0 = 0x800A07D7,
+    /// #VALUE!
+    Value = 0x800A07DF,
+    /// #REF!
+    Ref = 0x800A07E7,
+    /// #NAME?
+    Name = 0x800A07ED,
+    /// #NUM!
+    Num = 0x800A07F4,
+    /// #N/A
+    NA = 0x800A07FA,
+}
+
+// OLE automation dates count days from 1899-12-30. The integer part is
+// the signed day offset and the fraction is always the (positive) time of
+// day, so -1.25 is 1899-12-29 06:00.
+fn ole_epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1899, 12, 30).unwrap().and_hms_opt(0, 0, 0).unwrap()
+}
+
+pub fn ole_date_of_datetime(d: NaiveDateTime) -> f64 {
+    const DAY: i64 = 86_400_000;
+    let ms = (d - ole_epoch()).num_milliseconds();
+    let days = ms.div_euclid(DAY) as f64;
+    let frac = ms.rem_euclid(DAY) as f64 / DAY as f64;
+    if days >= 0. {
+        days + frac
+    } else {
+        days - frac
+    }
+}
+
+pub fn datetime_of_ole_date(d: f64) -> Option<DateTime<Utc>> {
+    if !d.is_finite() {
+        return None;
+    }
+    let days = d.trunc();
+    let frac = (d - days).abs();
+    let ms = (frac * 86_400_000.).round() as i64;
+    let d = ole_epoch()
+        .checked_add_signed(ChronoDuration::days(days as i64))?
+        .checked_add_signed(ChronoDuration::milliseconds(ms))?;
+    Some(Utc.from_utc_datetime(&d))
+}
+
+/// What a cell shows. This is everything a VARIANT handed to Excel can
+/// be, without depending on COM.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    /// an empty cell
+    Null,
+    Bool(bool),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    /// an exact decimal (VT_DECIMAL)
+    Decimal(Decimal),
+    /// currency in units of 1/10000 (VT_CY)
+    Currency(i64),
+    /// an OLE automation date (VT_DATE)
+    Date(f64),
+    String(Chars),
+    Error(ExcelError),
+}
+
+impl Cell {
+    fn string(s: String) -> Self {
+        Cell::String(Chars::from(s))
+    }
+}
+
+fn format_datetime(d: &DateTime<Utc>, opts: &Options) -> Cell {
+    match opts.datetime.unwrap_or_default() {
+        DateTimeFormat::Utc => Cell::Date(ole_date_of_datetime(d.naive_utc())),
+        DateTimeFormat::Local => {
+            Cell::Date(ole_date_of_datetime(d.with_timezone(&Local).naive_local()))
+        }
+        DateTimeFormat::Zone(tz) => {
+            Cell::Date(ole_date_of_datetime(d.with_timezone(&tz).naive_local()))
+        }
+        DateTimeFormat::Text => Cell::string(d.to_string()),
+    }
+}
+
+fn format_duration(d: &Duration, opts: &Options) -> Cell {
+    match opts.duration.unwrap_or_default() {
+        DurationFormat::Seconds => Cell::F64(d.as_secs_f64()),
+        DurationFormat::Days => Cell::F64(d.as_secs_f64() / 86400.),
+        DurationFormat::Text => Cell::string(format!("{}s", d.as_secs_f64())),
+    }
+}
+
+fn format_decimal(d: &Decimal, opts: &Options) -> Cell {
+    match opts.decimal.unwrap_or_default() {
+        DecimalFormat::Exact => Cell::Decimal(*d),
+        DecimalFormat::Currency => {
+            let cy = d.round_dp(4).checked_mul(Decimal::from(10_000));
+            match cy.and_then(|cy| cy.to_i64()) {
+                Some(cy) => Cell::Currency(cy),
+                None => Cell::Decimal(*d),
+            }
+        }
+        DecimalFormat::F64 => match f64::try_from(*d) {
+            Ok(v) => Cell::F64(v),
+            Err(e) => Cell::string(format!("ERR {}", e)),
+        },
+    }
+}
+
+fn format_value(v: &Value, opts: &Options) -> Cell {
+    match v {
+        Value::I32(v) | Value::Z32(v) => Cell::I32(*v),
+        Value::U32(v) | Value::V32(v) => Cell::U32(*v),
+        Value::I64(v) | Value::Z64(v) => Cell::I64(*v),
+        Value::U64(v) | Value::V64(v) => Cell::U64(*v),
+        Value::F32(v) => Cell::F32(*v),
+        Value::F64(v) => Cell::F64(*v),
+        Value::Decimal(d) => format_decimal(d, opts),
+        Value::True => Cell::Bool(true),
+        Value::False => Cell::Bool(false),
+        Value::String(s) => Cell::String(s.clone()),
+        Value::Bytes(_) => Cell::Error(ExcelError::Null),
+        Value::Null => Cell::Null,
+        Value::Ok => Cell::String(Chars::from("OK")),
+        Value::Error(_) => Cell::Error(ExcelError::Value),
+        Value::DateTime(d) => format_datetime(d, opts),
+        Value::Duration(d) => format_duration(d, opts),
+        Value::Array(_) => Cell::string(v.to_string()),
+    }
+}
+
+pub(crate) fn cell_of_value(v: &Value, opts: &Options) -> Cell {
+    format_value(&opts.apply(v), opts)
+}
+
+pub(crate) fn cell_of_event(e: &Event, opts: &Options) -> Cell {
+    match e {
+        Event::Update(v) => cell_of_value(v, opts),
+        Event::Unsubscribed => match (opts.errors, &opts.default) {
+            (Errors::Message, _) => Cell::String(Chars::from("unsubscribed")),
+            (_, Some(v)) => cell_of_value(v, opts),
+            (Errors::Excel, None) => Cell::Error(ExcelError::NA),
+            (Errors::Text, None) => Cell::String(Chars::from("#SUB")),
+            (Errors::Blank | Errors::Default, None) => Cell::Null,
+        },
+    }
+}
+
+/// the error shown in place of a topic that could not be connected
+pub(crate) fn cell_of_topic_error(errors: Errors, e: &anyhow::Error) -> Cell {
+    match errors {
+        Errors::Excel => Cell::Error(ExcelError::Ref),
+        Errors::Text => Cell::string(format!("#ERR {}", e)),
+        Errors::Message => Cell::string(e.to_string()),
+        Errors::Blank | Errors::Default => Cell::Null,
+    }
+}
+
+/// the error shown in place of a topic while netidx can't be initialized
+pub(crate) fn cell_of_init_error(errors: Errors, e: &str) -> Cell {
+    match errors {
+        Errors::Excel => Cell::Error(ExcelError::NA),
+        Errors::Text => Cell::string(format!("#INIT: {}", e)),
+        Errors::Message => Cell::string(e.to_string()),
+        Errors::Blank | Errors::Default => Cell::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn naive(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn serial_dates() {
+        assert_eq!(ole_date_of_datetime(naive(1899, 12, 30, 0, 0)), 0.);
+        assert_eq!(ole_date_of_datetime(naive(1900, 1, 1, 0, 0)), 2.);
+        assert_eq!(ole_date_of_datetime(naive(1970, 1, 1, 0, 0)), 25569.);
+        assert_eq!(ole_date_of_datetime(naive(2022, 3, 1, 12, 0)), 44621.5);
+        assert_eq!(ole_date_of_datetime(naive(1899, 12, 29, 6, 0)), -1.25);
+        assert_eq!(ole_date_of_datetime(naive(1899, 12, 29, 0, 0)), -1.);
+    }
+
+    #[test]
+    fn serial_date_round_trip() {
+        for d in [
+            naive(1899, 12, 29, 6, 0),
+            naive(1899, 12, 30, 18, 0),
+            naive(1970, 1, 1, 0, 0),
+            naive(2023, 6, 15, 18, 30),
+            naive(2100, 2, 28, 23, 59),
+        ] {
+            let d = Utc.from_utc_datetime(&d);
+            assert_eq!(datetime_of_ole_date(ole_date_of_datetime(d.naive_utc())), Some(d))
+        }
+    }
+
+    #[test]
+    fn datetime() {
+        let d = Utc.with_ymd_and_hms(2022, 3, 1, 12, 0, 0).unwrap();
+        let utc = Options { datetime: Some(DateTimeFormat::Utc), ..Options::default() };
+        assert_eq!(cell_of_value(&Value::DateTime(d), &utc), Cell::Date(44621.5));
+        let ny = Options {
+            datetime: Some("America/New_York".parse().unwrap()),
+            ..Options::default()
+        };
+        let d_ = Utc.with_ymd_and_hms(2022, 3, 1, 17, 0, 0).unwrap();
+        assert_eq!(cell_of_value(&Value::DateTime(d_), &ny), Cell::Date(44621.5));
+        let text = Options { datetime: Some(DateTimeFormat::Text), ..Options::default() };
+        assert_eq!(cell_of_value(&Value::DateTime(d), &text), Cell::string(d.to_string()))
+    }
+
+    #[test]
+    fn duration() {
+        let d = Value::Duration(Duration::from_secs(43200));
+        let secs =
+            Options { duration: Some(DurationFormat::Seconds), ..Options::default() };
+        assert_eq!(cell_of_value(&d, &secs), Cell::F64(43200.));
+        let days = Options { duration: Some(DurationFormat::Days), ..Options::default() };
+        assert_eq!(cell_of_value(&d, &days), Cell::F64(0.5))
+    }
+
+    #[test]
+    fn decimal() {
+        let d = Decimal::new(-1234567890123456789, 9);
+        let exact = Options { decimal: Some(DecimalFormat::Exact), ..Options::default() };
+        assert_eq!(cell_of_value(&Value::Decimal(d), &exact), Cell::Decimal(d));
+        let cy = Options { decimal: Some(DecimalFormat::Currency), ..Options::default() };
+        assert_eq!(
+            cell_of_value(&Value::Decimal(d), &cy),
+            Cell::Currency(-12345678901235)
+        );
+        let f = Options { decimal: Some(DecimalFormat::F64), ..Options::default() };
+        match cell_of_value(&Value::Decimal(d), &f) {
+            Cell::F64(v) => assert!((v + 1_234_567_890.123_456_7).abs() < 1e-6),
+            c => panic!("expected an f64, got {:?}", c),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let e = Value::Error(Chars::from("no such path"));
+        assert_eq!(
+            cell_of_value(&e, &Options::default()),
+            Cell::Error(ExcelError::Value)
+        );
+        let text = Options { errors: Errors::Text, ..Options::default() };
+        assert_eq!(cell_of_value(&e, &text), Cell::string("#ERR no such path".into()));
+        let msg = Options { errors: Errors::Message, ..Options::default() };
+        assert_eq!(cell_of_value(&e, &msg), Cell::string("no such path".into()));
+        assert_eq!(cell_of_value(&Value::I32(42), &msg), Cell::Null);
+        assert_eq!(
+            cell_of_event(&Event::Unsubscribed, &Options::default()),
+            Cell::Error(ExcelError::NA)
+        )
+    }
+
+    #[test]
+    fn index() {
+        let row =
+            |a: i32, b: i32| Value::Array(Arc::from(vec![Value::I32(a), Value::I32(b)]));
+        let m = Value::Array(Arc::from(vec![row(1, 2), row(3, 4)]));
+        let opts = Options::parse(["[1][0]"]).unwrap();
+        assert_eq!(cell_of_value(&m, &opts), Cell::I32(3));
+        let opts = Options::parse(["index=[1]"]).unwrap();
+        assert_eq!(cell_of_value(&row(1, 2), &opts), Cell::I32(2));
+        let opts = Options::parse(["[2]", "errors=message"]).unwrap();
+        assert_eq!(cell_of_value(&m, &opts), Cell::string("no element [2]".into()));
+        assert!(Options::parse(["[x]"]).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    cell::Cell,
+    comglue::variant::{SafeArray, Variant},
+};
+use anyhow::{anyhow, bail, Result};
+use chrono::prelude::*;
+use netidx::{
+    chars::Chars,
+    subscriber::{Typ, Value},
+};
+use rust_decimal::Decimal;
+use std::sync::Arc;
+use windows::Win32::System::Com::{
+    VARENUM, VT_ARRAY, VT_BOOL, VT_BSTR, VT_CY, VT_DATE, VT_DECIMAL, VT_EMPTY, VT_I4,
+    VT_I8, VT_NULL, VT_R4, VT_R8, VT_UI4, VT_UI8, VT_VARIANT,
+};
+
+pub fn variant_of_cell(c: &Cell) -> Variant {
+    match c {
+        Cell::Null => Variant::null(),
+        Cell::Bool(b) => Variant::from(*b),
+        Cell::I32(v) => Variant::from(*v),
+        Cell::U32(v) => Variant::from(*v),
+        Cell::I64(v) => Variant::from(*v),
+        Cell::U64(v) => Variant::from(*v),
+        Cell::F32(v) => Variant::from(*v),
+        Cell::F64(v) => Variant::from(*v),
+        Cell::Decimal(d) => Variant::from(*d),
+        Cell::Currency(cy) => Variant::currency(*cy),
+        Cell::Date(d) => Variant::date(*d),
+        Cell::String(s) => Variant::from(&**s),
+        Cell::Error(e) => Variant::from(*e),
+    }
+}
+
+// one dimensional arrays become arrays, two dimensional arrays (e.g. a
+// range) become an array of rows.
+fn value_of_safearray(a: &SafeArray) -> Result<Value> {
+    let a = a.read()?;
+    let bounds = a.bounds()?;
+    let range = |i: usize| {
+        let b = &bounds[i];
+        b.lLbound..(b.lLbound + b.cElements as i32)
+    };
+    let elts = match bounds.len() {
+        1 => range(0)
+            .map(|i| value_of_variant(a.get(&[i])?, None))
+            .collect::<Result<Vec<_>>>()?,
+        2 => range(0)
+            .map(|r| {
+                let row = range(1)
+                    .map(|c| value_of_variant(a.get(&[r, c])?, None))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Value::Array(Arc::from(row)))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        n => bail!("can't convert a {} dimensional array", n),
+    };
+    Ok(Value::Array(Arc::from(elts)))
+}
+
+/// Convert a variant to a netidx value. If `hint` is specified then the
+/// result will be cast to that type, strings will be parsed as that type.
+pub fn value_of_variant(v: &Variant, hint: Option<Typ>) -> Result<Value> {
+    let typ = v.typ();
+    let res = if typ == VT_EMPTY || typ == VT_NULL {
+        Value::Null
+    } else if typ == VT_BOOL {
+        let b: bool = v.try_into()?;
+        if b {
+            Value::True
+        } else {
+            Value::False
+        }
+    } else if typ == VT_I4 {
+        Value::I32(v.try_into()?)
+    } else if typ == VT_UI4 {
+        Value::U32(v.try_into()?)
+    } else if typ == VT_I8 {
+        Value::I64(v.try_into()?)
+    } else if typ == VT_UI8 {
+        Value::U64(v.try_into()?)
+    } else if typ == VT_R4 {
+        Value::F32(v.try_into()?)
+    } else if typ == VT_R8 {
+        Value::F64(v.try_into()?)
+    } else if typ == VT_DATE {
+        let d: DateTime<Utc> = v.try_into()?;
+        Value::DateTime(d)
+    } else if typ == VT_CY || typ == VT_DECIMAL {
+        let d: Decimal = v.try_into()?;
+        Value::Decimal(d)
+    } else if typ == VT_BSTR {
+        let s: String = v.try_into()?;
+        match hint {
+            None => return Ok(Value::String(Chars::from(s))),
+            Some(typ) => return typ.parse(&s),
+        }
+    } else if typ == VARENUM(VT_ARRAY.0 | VT_VARIANT.0) {
+        let a: &SafeArray = v.try_into()?;
+        value_of_safearray(a)?
+    } else {
+        bail!("unsupported variant type {:?}", typ)
+    };
+    match hint {
+        None => Ok(res),
+        Some(typ) => {
+            let err = anyhow!("can't cast {} to {:?}", res, typ);
+            res.cast(typ).ok_or(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cell::{cell_of_value, ExcelError},
+        options::Options,
+    };
+    use windows::Win32::System::Com::VT_ERROR;
+
+    fn round_trip(v: Value) {
+        let var = variant_of_cell(&cell_of_value(&v, &Options::default()));
+        assert_eq!(value_of_variant(&var, None).unwrap(), v)
+    }
+
+    #[test]
+    fn scalars() {
+        round_trip(Value::I32(-42));
+        round_trip(Value::U32(42));
+        round_trip(Value::I64(-42_000_000_000));
+        round_trip(Value::U64(42_000_000_000));
+        round_trip(Value::F32(4.2));
+        round_trip(Value::F64(-4.2e100));
+        round_trip(Value::True);
+        round_trip(Value::False);
+        round_trip(Value::Null);
+        round_trip(Value::String(Chars::from("hello world")));
+        round_trip(Value::Decimal(Decimal::new(-1234567890123456789, 9)));
+        round_trip(Value::DateTime(Utc.with_ymd_and_hms(2022, 3, 1, 10, 30, 0).unwrap()));
+    }
+
+    #[test]
+    fn cells() {
+        let cy = variant_of_cell(&Cell::Currency(1234500));
+        assert_eq!(
+            value_of_variant(&cy, None).unwrap(),
+            Value::Decimal(Decimal::new(12345, 2))
+        );
+        let d = variant_of_cell(&Cell::Date(44621.5));
+        let expected = Utc.with_ymd_and_hms(2022, 3, 1, 12, 0, 0).unwrap();
+        assert_eq!(value_of_variant(&d, None).unwrap(), Value::DateTime(expected));
+        assert_eq!(variant_of_cell(&Cell::Error(ExcelError::NA)).typ(), VT_ERROR);
+        assert!(variant_of_cell(&Cell::Null).is_null())
+    }
+
+    #[test]
+    fn hints() {
+        let v = Variant::from(42);
+        assert_eq!(value_of_variant(&v, Some(Typ::U64)).unwrap(), Value::U64(42));
+        assert_eq!(value_of_variant(&v, Some(Typ::F64)).unwrap(), Value::F64(42.));
+        let s = Variant::from("42");
+        assert_eq!(value_of_variant(&s, Some(Typ::I64)).unwrap(), Value::I64(42));
+        assert!(value_of_variant(&s, Some(Typ::Bool)).is_err());
+        let d = Variant::from("2022-03-01T10:30:00Z");
+        let expected = Utc.with_ymd_and_hms(2022, 3, 1, 10, 30, 0).unwrap();
+        assert_eq!(
+            value_of_variant(&d, Some(Typ::DateTime)).unwrap(),
+            Value::DateTime(expected)
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(value_of_variant(&Variant::new(), None).unwrap(), Value::Null)
+    }
+}
@@ -1,6 +1,9 @@
-use crate::comglue::{
-    interface::IID_IDISPATCH,
-    variant::{str_to_wstr, Variant},
+use crate::{
+    comglue::{
+        interface::IID_IDISPATCH,
+        variant::{str_to_wstr, Variant},
+    },
+    notify::Notify,
 };
 use anyhow::{anyhow, Result};
 use log::{debug, error};
@@ -139,3 +142,9 @@ impl IRTDUpdateEventWrap {
         let _ = self.0.send(());
     }
 }
+
+impl Notify for IRTDUpdateEventWrap {
+    fn notify(&self) {
+        self.update_notify()
+    }
+}
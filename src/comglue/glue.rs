@@ -1,18 +1,18 @@
 use crate::{
+    cell::ExcelError,
     comglue::{
+        convert::{value_of_variant, variant_of_cell},
         dispatch::IRTDUpdateEventWrap,
         interface::{IDispatch, IRTDServer, IRTDUpdateEvent},
         variant::{string_from_wstr, SafeArray, Variant},
     },
     server::{Server, TopicId},
+    topic,
 };
 use anyhow::{bail, Result};
 use com::sys::{HRESULT, IID, NOERROR};
 use log::{debug, error};
-use netidx::{
-    path::Path,
-    subscriber::{Event, Value},
-};
+use netidx::{chars::Chars, subscriber::Value};
 use windows::Win32::System::Com::{
     ITypeInfo, DISPPARAMS, EXCEPINFO, SAFEARRAY, SAFEARRAYBOUND, VARIANT,
 };
@@ -65,62 +65,30 @@ unsafe fn dispatch_server_start(server: &Server, params: Params) -> Result<()> {
     Ok(())
 }
 
-unsafe fn dispatch_connect_data(server: &Server, params: Params) -> Result<()> {
-    let topic_id = TopicId(params.get(2)?.try_into()?);
-    let topics: &SafeArray = params.get(1)?.try_into()?;
-    let topics = topics.read()?;
-    let path = match topics.iter()?.next() {
-        None => bail!("not enough topics"),
-        Some(v) => {
-            let path: String = v.try_into()?;
-            Path::from(path)
-        }
-    };
-    Ok(server.connect_data(topic_id, path)?)
-}
-
-fn variant_of_value(v: &Value) -> Variant {
-    match v {
-        Value::I32(v) | Value::Z32(v) => Variant::from(*v),
-        Value::U32(v) | Value::V32(v) => Variant::from(*v),
-        Value::I64(v) | Value::Z64(v) => Variant::from(*v),
-        Value::U64(v) | Value::V64(v) => Variant::from(*v),
-        Value::F32(v) => Variant::from(*v),
-        Value::F64(v) => Variant::from(*v),
-        Value::Decimal(d) => match f64::try_from(*d) {
-            Ok(v) => Variant::from(v),
-            Err(e) => Variant::from(&format!("ERR {}", e)),
-        },
-        Value::True => Variant::from(true),
-        Value::False => Variant::from(false),
-        Value::String(s) => Variant::from(&**s),
-        Value::Bytes(_) => Variant::from("#BIN"),
-        Value::Null => Variant::null(),
-        Value::Ok => Variant::from("OK"),
-        Value::Error(e) => Variant::from(&format!("#ERR {}", &**e)),
-        Value::DateTime(d) => Variant::from(&d.to_string()),
-        Value::Duration(d) => Variant::from(&format!("{}s", d.as_secs_f64())),
-        Value::Array(_) => Variant::from(&format!("{}", v)),
-        /* sadly this doesn't work {
-            let mut res = SafeArray::new(&[
-                SAFEARRAYBOUND { lLbound: 0, cElements: a.len() as u32 },
-            ]);
-            {
-                let mut res = res.write().unwrap();
-                for (i, v) in a.iter().enumerate() {
-                    *res.get_mut(&[i as i32]).unwrap() = variant_of_value(v);
-                }
-            }
-            Variant::from(res)
-        }*/
+// topic strings are taken as they are, anything else Excel hands us (e.g.
+// a number or a range passed to write) is converted.
+fn value_of_topic(v: &Variant) -> Result<Value> {
+    let s: Result<String> = v.try_into();
+    match s {
+        Ok(s) => Ok(Value::String(Chars::from(s))),
+        Err(_) => value_of_variant(v, None),
     }
 }
 
-fn variant_of_event(e: &Event) -> Variant {
-    match e {
-        Event::Unsubscribed => Variant::from("#SUB"),
-        Event::Update(v) => variant_of_value(v),
-    }
+unsafe fn dispatch_connect_data(
+    server: &Server,
+    params: Params,
+    result: &mut Variant,
+) -> Result<()> {
+    let tid = TopicId(params.get(2)?.try_into()?);
+    let topics: &SafeArray = params.get(1)?.try_into()?;
+    let topics =
+        topics.read()?.iter()?.map(value_of_topic).collect::<Result<Vec<_>>>()?;
+    *result = match topic::connect(server, tid, &topics) {
+        Ok(()) => Variant::from(1),
+        Err(cell) => variant_of_cell(&cell),
+    };
+    Ok(())
 }
 
 unsafe fn dispatch_refresh_data(
@@ -130,7 +98,7 @@ unsafe fn dispatch_refresh_data(
 ) -> Result<()> {
     let ntopics = params.get_mut(0)?;
     let ntopics: &mut i32 = ntopics.try_into()?;
-    let mut updates = server.refresh_data();
+    let updates = server.refresh_data();
     let len = updates.len();
     *ntopics = len as i32;
     let mut array = SafeArray::new(&[
@@ -139,9 +107,9 @@ unsafe fn dispatch_refresh_data(
     ]);
     {
         let mut wh = array.write()?;
-        for (i, (TopicId(tid), e)) in updates.drain().enumerate() {
-            *wh.get_mut(&[0, i as i32])? = Variant::from(tid);
-            *wh.get_mut(&[1, i as i32])? = variant_of_event(&e);
+        for (i, (TopicId(tid), cell)) in updates.iter().enumerate() {
+            *wh.get_mut(&[0, i as i32])? = Variant::from(*tid);
+            *wh.get_mut(&[1, i as i32])? = variant_of_cell(cell);
         }
     }
     *result = Variant::from(array);
@@ -241,11 +209,11 @@ com::class! {
                 },
                 2 => {
                     debug!("ConnectData");
-                    match dispatch_connect_data(&self.server, params) {
-                        Ok(()) => { *result = Variant::from(1); },
+                    match dispatch_connect_data(&self.server, params, result) {
+                        Ok(()) => (),
                         Err(e) => {
                             error!("connect_data invalid arg {}", e);
-                            *result = Variant::error();
+                            *result = Variant::from(ExcelError::Ref);
                         }
                     }
                 },
@@ -1,4 +1,7 @@
+use crate::cell::{datetime_of_ole_date, ole_date_of_datetime, ExcelError};
 use anyhow::{anyhow, bail, Error, Result};
+use chrono::prelude::*;
+use rust_decimal::Decimal;
 use std::{
     convert::{From, TryInto},
     default::Default,
@@ -17,8 +20,9 @@ use windows::{
         System::{
             Com::{
                 IDispatch, SAFEARRAY, SAFEARRAYBOUND, VARENUM, VARIANT, VARIANT_0_0_0,
-                VT_ARRAY, VT_BOOL, VT_BSTR, VT_BYREF, VT_DISPATCH, VT_ERROR, VT_I4,
-                VT_I8, VT_NULL, VT_R4, VT_R8, VT_UI4, VT_UI8, VT_VARIANT,
+                VT_ARRAY, VT_BOOL, VT_BSTR, VT_BYREF, VT_CY, VT_DATE, VT_DECIMAL,
+                VT_DISPATCH, VT_EMPTY, VT_ERROR, VT_I4, VT_I8, VT_NULL, VT_R4, VT_R8,
+                VT_UI4, VT_UI8, VT_VARIANT,
             },
             Ole::{
                 SafeArrayCreate, SafeArrayDestroy, SafeArrayGetDim, SafeArrayGetLBound,
@@ -33,6 +37,8 @@ pub unsafe fn string_from_wstr<'a>(s: *mut u16) -> OsString {
     OsString::from_wide(std::slice::from_raw_parts(s, lstrlenW(PCWSTR(s)) as usize))
 }
 
+const DECIMAL_NEG: u8 = 0x80;
+
 pub fn str_to_wstr(s: &str) -> Vec<u16> {
     let mut v = OsString::from(s).encode_wide().collect::<Vec<_>>();
     v.push(0);
@@ -85,6 +91,109 @@ impl<'a> TryInto<i32> for &'a Variant {
     }
 }
 
+impl<'a> TryInto<u32> for &'a Variant {
+    type Error = Error;
+
+    fn try_into(self) -> Result<u32, Self::Error> {
+        if self.typ() != VT_UI4 {
+            bail!("not a u32")
+        } else {
+            unsafe { Ok(self.val().ulVal) }
+        }
+    }
+}
+
+impl<'a> TryInto<i64> for &'a Variant {
+    type Error = Error;
+
+    fn try_into(self) -> Result<i64, Self::Error> {
+        if self.typ() != VT_I8 {
+            bail!("not an i64")
+        } else {
+            unsafe { Ok(self.val().llVal) }
+        }
+    }
+}
+
+impl<'a> TryInto<u64> for &'a Variant {
+    type Error = Error;
+
+    fn try_into(self) -> Result<u64, Self::Error> {
+        if self.typ() != VT_UI8 {
+            bail!("not a u64")
+        } else {
+            unsafe { Ok(self.val().ullVal) }
+        }
+    }
+}
+
+impl<'a> TryInto<f32> for &'a Variant {
+    type Error = Error;
+
+    fn try_into(self) -> Result<f32, Self::Error> {
+        if self.typ() != VT_R4 {
+            bail!("not an f32")
+        } else {
+            unsafe { Ok(self.val().fltVal) }
+        }
+    }
+}
+
+impl<'a> TryInto<f64> for &'a Variant {
+    type Error = Error;
+
+    fn try_into(self) -> Result<f64, Self::Error> {
+        if self.typ() != VT_R8 {
+            bail!("not an f64")
+        } else {
+            unsafe { Ok(self.val().dblVal) }
+        }
+    }
+}
+
+impl<'a> TryInto<DateTime<Utc>> for &'a Variant {
+    type Error = Error;
+
+    fn try_into(self) -> Result<DateTime<Utc>, Self::Error> {
+        if self.typ() != VT_DATE {
+            bail!("not a date")
+        } else {
+            let d = unsafe { self.val().date };
+            datetime_of_ole_date(d).ok_or_else(|| anyhow!("date out of range {}", d))
+        }
+    }
+}
+
+// both VT_CY and VT_DECIMAL are exact decimal representations
+impl<'a> TryInto<Decimal> for &'a Variant {
+    type Error = Error;
+
+    fn try_into(self) -> Result<Decimal, Self::Error> {
+        if self.typ() == VT_CY {
+            Ok(Decimal::new(unsafe { self.val().cyVal.int64 }, 4))
+        } else if self.typ() == VT_DECIMAL {
+            unsafe {
+                // the DECIMAL overlays the whole VARIANT, including the type tag
+                let d = &self.0.Anonymous.decVal;
+                let scale = d.Anonymous.Anonymous.scale as u32;
+                if scale > 28 {
+                    bail!("invalid decimal scale {}", scale)
+                }
+                let lo = d.Anonymous2.Lo64;
+                Ok(Decimal::from_parts(
+                    lo as u32,
+                    (lo >> 32) as u32,
+                    d.Hi32,
+                    d.Anonymous.Anonymous.sign & DECIMAL_NEG != 0,
+                    scale,
+                ))
+            }
+        } else {
+            bail!("not a decimal")
+        }
+    }
+}
+
 impl<'a> TryInto<&'a mut i32> for &'a mut Variant {
     type Error = Error;
 
@@ -234,6 +343,41 @@ impl From<f64> for Variant {
     }
 }
 
+impl From<DateTime<Utc>> for Variant {
+    fn from(d: DateTime<Utc>) -> Self {
+        Variant::date(ole_date_of_datetime(d.naive_utc()))
+    }
+}
+
+impl From<Decimal> for Variant {
+    fn from(d: Decimal) -> Self {
+        let mut v = Self::new();
+        let m = d.mantissa().unsigned_abs();
+        unsafe {
+            let dec = &mut v.0.Anonymous.decVal;
+            dec.Anonymous.Anonymous.scale = d.scale() as u8;
+            dec.Anonymous.Anonymous.sign =
+                if d.is_sign_negative() { DECIMAL_NEG } else { 0 };
+            dec.Hi32 = (m >> 64) as u32;
+            dec.Anonymous2.Lo64 = m as u64;
+            // the DECIMAL overlays the type tag, so it must be set last
+            v.set_typ(VT_DECIMAL);
+        }
+        v
+    }
+}
+
+impl From<ExcelError> for Variant {
+    fn from(e: ExcelError) -> Self {
+        let mut v = Self::new();
+        unsafe {
+            v.set_typ(VT_ERROR);
+            v.val_mut().scode = e as u32 as i32;
+            v
+        }
+    }
+}
+
 impl From<&str> for Variant {
     fn from(s: &str) -> Self {
         let mut v = Self::new();
@@ -282,6 +426,26 @@ impl Variant {
         v
     }
 
+    /// an OLE automation date, see `ole_date_of_datetime`
+    pub fn date(d: f64) -> Variant {
+        let mut v = Self::new();
+        unsafe {
+            v.set_typ(VT_DATE);
+            v.val_mut().date = d;
+            v
+        }
+    }
+
+    /// a currency value, fixed point with 4 decimal places
+    pub fn currency(cy: i64) -> Variant {
+        let mut v = Self::new();
+        unsafe {
+            v.set_typ(VT_CY);
+            v.val_mut().cyVal.int64 = cy;
+            v
+        }
+    }
+
     pub fn error() -> Variant {
         let mut v = Self::default();
         unsafe { v.set_typ(VT_ERROR) }
@@ -308,6 +472,14 @@ impl Variant {
         mem::transmute::<*mut VARIANT, &'a mut Variant>(p)
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.typ() == VT_EMPTY
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.typ() == VT_NULL
+    }
+
     pub fn typ(&self) -> VARENUM {
         unsafe { self.0.Anonymous.Anonymous.vt }
     }
@@ -586,3 +758,39 @@ impl SafeArray {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decimals() {
+        for d in [
+            Decimal::new(12345, 2),
+            Decimal::new(-12345, 2),
+            Decimal::from_i128_with_scale(79_228_162_514_264_337_593_543_950_335, 28),
+            Decimal::from_i128_with_scale(-1, 28),
+            Decimal::ZERO,
+        ] {
+            let v = Variant::from(d);
+            assert_eq!(v.typ(), VT_DECIMAL);
+            let d_: Decimal = (&v).try_into().unwrap();
+            assert_eq!(d_, d);
+            assert_eq!(d_.scale(), d.scale())
+        }
+        let v = Variant::currency(1234500);
+        assert_eq!(v.typ(), VT_CY);
+        let d: Decimal = (&v).try_into().unwrap();
+        assert_eq!(d, Decimal::new(12345, 2))
+    }
+
+    #[test]
+    fn date_variant() {
+        let d = Utc.with_ymd_and_hms(2022, 3, 1, 12, 0, 0).unwrap();
+        let v = Variant::from(d);
+        assert_eq!(v.typ(), VT_DATE);
+        assert_eq!(unsafe { v.val().date }, 44621.5);
+        let d_: DateTime<Utc> = (&v).try_into().unwrap();
+        assert_eq!(d_, d)
+    }
+}
@@ -0,0 +1,480 @@
+use crate::options::{Conflate, DateTimeFormat, DecimalFormat, DurationFormat};
+use anyhow::{anyhow, bail, Result};
+use dirs;
+use log::{error, info, warn, LevelFilter};
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use simplelog;
+use std::{
+    collections::BTreeMap,
+    default::Default,
+    fs::{self, File},
+    mem,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    result,
+    sync::{Arc, Once},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Auth {
+    Anonymous,
+    Kerberos,
+    Tls,
+}
+
+/// What happens when updates arrive faster than the server can take them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// wait, this slows down every subscription in the process
+    #[default]
+    Block,
+    /// drop the updates, and re-read the last value of the paths they
+    /// were for once there is room again
+    Drop,
+}
+
+/// A netidx cluster that topics can select by name, e.g. uat:/risk/pnl
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub auth_mechanism: Option<Auth>,
+    /// a netidx client config file
+    #[serde(default)]
+    pub netidx_config: Option<PathBuf>,
+    /// a netidx client config given inline, in the same format as the
+    /// file
+    #[serde(default)]
+    pub resolver: Option<serde_json::Value>,
+}
+
+impl Profile {
+    fn check(&self) -> Result<()> {
+        if self.netidx_config.is_some() && self.resolver.is_some() {
+            bail!("netidx_config and resolver can't both be set")
+        }
+        Ok(())
+    }
+
+    /// The netidx client config to use. The inline resolver config if
+    /// there is one, otherwise the netidx_config file, otherwise the
+    /// default netidx config.
+    pub fn netidx(&self) -> Result<netidx::config::Config> {
+        match (&self.resolver, &self.netidx_config) {
+            (Some(resolver), _) => netidx::config::Config::parse(&resolver.to_string()),
+            (None, Some(path)) => netidx::config::Config::load(path),
+            (None, None) => netidx::config::Config::load_default(),
+        }
+    }
+}
+
+// unknown fields are rejected so that a misspelled setting is reported
+// rather than ignored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub log_level: LevelFilter,
+    #[serde(default)]
+    pub auth_mechanism: Option<Auth>,
+    #[serde(default)]
+    pub publish_base: Option<String>,
+    #[serde(default)]
+    pub publish_bind: Option<String>,
+    #[serde(default)]
+    pub datetime: DateTimeFormat,
+    #[serde(default)]
+    pub duration: DurationFormat,
+    #[serde(default)]
+    pub decimal: DecimalFormat,
+    /// conflation for paths under each prefix, the longest matching
+    /// prefix wins
+    #[serde(default)]
+    pub conflate: BTreeMap<String, Conflate>,
+    /// the most topics handed to Excel in one RefreshData, the rest are
+    /// delivered in the next one
+    #[serde(default)]
+    pub max_refresh_batch: Option<NonZeroUsize>,
+    /// how many batches of updates from the subscriber may be queued,
+    /// the default is 3
+    #[serde(default)]
+    pub update_channel_depth: Option<usize>,
+    #[serde(default)]
+    pub update_overflow: Overflow,
+    /// a netidx client config file to use instead of the default one
+    #[serde(default)]
+    pub netidx_config: Option<PathBuf>,
+    /// a netidx client config given inline, in the same format as the
+    /// file, e.g. the resolver addresses and auth
+    #[serde(default)]
+    pub resolver: Option<serde_json::Value>,
+    /// other netidx clusters, by name
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// why the config file couldn't be used, if it couldn't, in which
+    /// case the rest of the config is the default
+    #[serde(skip)]
+    pub load_error: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_level: LevelFilter::Off,
+            auth_mechanism: None,
+            publish_base: None,
+            publish_bind: None,
+            datetime: DateTimeFormat::default(),
+            duration: DurationFormat::default(),
+            decimal: DecimalFormat::default(),
+            conflate: BTreeMap::new(),
+            max_refresh_batch: None,
+            update_channel_depth: None,
+            update_overflow: Overflow::default(),
+            netidx_config: None,
+            resolver: None,
+            profiles: BTreeMap::new(),
+            load_error: None,
+        }
+    }
+}
+
+// prefixes match whole path components, /foo is a prefix of /foo/bar but
+// not of /foobar
+fn is_prefix(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        None => false,
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+    }
+}
+
+impl Config {
+    /// parse a config file, errors include the line and column where
+    /// the problem is
+    pub fn parse(s: &str) -> Result<Config> {
+        let config: Config = serde_json::from_str(s)?;
+        config.default_profile().check()?;
+        for (name, profile) in &config.profiles {
+            profile.check().map_err(|e| anyhow!("profile {}: {}", name, e))?
+        }
+        Ok(config)
+    }
+
+    /// the netidx settings used by topics that don't name a profile
+    pub fn default_profile(&self) -> Profile {
+        Profile {
+            auth_mechanism: self.auth_mechanism,
+            netidx_config: self.netidx_config.clone(),
+            resolver: self.resolver.clone(),
+        }
+    }
+
+    /// the netidx client config used by topics that don't name a profile
+    pub fn netidx(&self) -> Result<netidx::config::Config> {
+        self.default_profile().netidx()
+    }
+
+    /// true if going from this config to `other` means a new subscriber
+    pub fn netidx_changed(&self, other: &Config) -> bool {
+        self.auth_mechanism != other.auth_mechanism
+            || self.netidx_config != other.netidx_config
+            || self.resolver != other.resolver
+    }
+
+    /// true if going from this config to `other` means netidx has to be
+    /// initialized again
+    pub fn needs_reinit(&self, other: &Config) -> bool {
+        self.netidx_changed(other)
+            || self.profiles != other.profiles
+            || self.publish_base != other.publish_base
+            || self.publish_bind != other.publish_bind
+            || self.update_channel_depth != other.update_channel_depth
+            || self.update_overflow != other.update_overflow
+    }
+
+    /// the conflation configured for `path`
+    pub fn conflate_for(&self, path: &str) -> Conflate {
+        self.conflate
+            .iter()
+            .filter(|(prefix, _)| is_prefix(prefix, path))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, c)| *c)
+            .unwrap_or_default()
+    }
+}
+
+fn base_dir() -> PathBuf {
+    let path = match dirs::config_dir() {
+        Some(d) => d,
+        None => match dirs::home_dir() {
+            Some(d) => d,
+            None => PathBuf::from("\\"),
+        },
+    };
+    path.join("netidx-excel")
+}
+
+// if the config file can't be used the error says why, and which file
+fn read(path: &Path) -> result::Result<Config, String> {
+    fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| Config::parse(&s))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// if the config file is broken the log level we were asked for is
+// unknown, make sure the reason is written down somewhere
+fn log_level(config: &Config) -> LevelFilter {
+    match config.load_error {
+        None => config.log_level,
+        Some(_) => config.log_level.max(LevelFilter::Warn),
+    }
+}
+
+// a missing config is replaced by the defaults, so there is something to
+// edit
+fn create_default(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?
+    }
+    fs::write(path, serde_json::to_string_pretty(&Config::default())?)?;
+    Ok(())
+}
+
+fn load_config(path: &Path) -> Config {
+    let res = if path.exists() {
+        read(path)
+    } else {
+        create_default(path)
+            .map(|()| Config::default())
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    };
+    match res {
+        Ok(config) => config,
+        Err(e) => Config { load_error: Some(e), ..Config::default() },
+    }
+}
+
+// if log.txt can't be written, e.g. the directory is read only, the
+// temp dir is tried instead, and failing that nothing is logged
+fn init_log(path: &Path, level: LevelFilter) {
+    let fallback = std::env::temp_dir().join("netidx-excel-log.txt");
+    let (log, err) = match File::create(path) {
+        Ok(log) => (log, None),
+        Err(e) => match File::create(&fallback) {
+            Ok(log) => (log, Some(e)),
+            Err(_) => return,
+        },
+    };
+    // the logger lets everything through, what is actually logged is
+    // decided by the max level, so it can change when the config does
+    let config = simplelog::Config::default();
+    if simplelog::WriteLogger::init(LevelFilter::Trace, config, log).is_ok() {
+        log::set_max_level(level);
+        if let Some(e) = err {
+            warn!(
+                "could not create {}, logging to {}: {}",
+                path.display(),
+                fallback.display(),
+                e
+            )
+        }
+    }
+}
+
+// a broken file doesn't throw away settings that were working, the
+// current config is kept, along with the reason the new one wasn't used
+fn reload(path: &Path, current: &Config) -> Config {
+    match read(path) {
+        Ok(config) => {
+            info!("reloaded {}", path.display());
+            config
+        }
+        Err(e) => {
+            error!("keeping the current config, could not reload {}", e);
+            Config { load_error: Some(e), ..current.clone() }
+        }
+    }
+}
+
+static CURRENT: Lazy<RwLock<Arc<Config>>> = Lazy::new(|| {
+    let base = base_dir();
+    // the config is read first, a problem with the log must not cost us
+    // the settings
+    let config = load_config(&base.join("config.json"));
+    init_log(&base.join("log.txt"), log_level(&config));
+    if let Some(e) = &config.load_error {
+        error!("using the default config, could not load {}", e)
+    }
+    RwLock::new(Arc::new(config))
+});
+
+/// The config loaded from config.json, it changes when the file does
+pub fn current() -> Arc<Config> {
+    CURRENT.read().clone()
+}
+
+type Listener = Box<dyn FnMut(&Arc<Config>) -> bool + Send>;
+
+static LISTENERS: Lazy<Mutex<Vec<Listener>>> = Lazy::new(|| Mutex::new(vec![]));
+static WATCH: Once = Once::new();
+
+// how often config.json is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn watch() {
+    let path = base_dir().join("config.json");
+    let mut last = modified(&path);
+    loop {
+        thread::sleep(WATCH_INTERVAL);
+        let m = modified(&path);
+        if m != last {
+            last = m;
+            let config = Arc::new(reload(&path, &current()));
+            log::set_max_level(log_level(&config));
+            *CURRENT.write() = config.clone();
+            // reloading a server can take a while, LISTENERS isn't held
+            // meanwhile so new servers can still register. They start
+            // from the new config already.
+            let mut listeners = mem::take(&mut *LISTENERS.lock());
+            listeners.retain_mut(|f| f(&config));
+            let mut current = LISTENERS.lock();
+            listeners.append(&mut current);
+            *current = listeners;
+        }
+    }
+}
+
+/// Call `f` with the new config whenever config.json changes, for as
+/// long as it returns true. The log level is changed before `f` is
+/// called.
+pub fn on_reload(f: impl FnMut(&Arc<Config>) -> bool + Send + 'static) {
+    LISTENERS.lock().push(Box::new(f));
+    WATCH.call_once(|| {
+        let res = thread::Builder::new().name("netidx-config".into()).spawn(watch);
+        if let Err(e) = res {
+            error!("could not watch the config file {}", e)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflate_prefixes() {
+        let mut cfg = Config::default();
+        cfg.conflate.insert("/market".into(), Conflate::First);
+        cfg.conflate.insert("/market/trades/".into(), Conflate::List);
+        assert_eq!(cfg.conflate_for("/market/quotes/ibm"), Conflate::First);
+        assert_eq!(cfg.conflate_for("/market/trades/ibm"), Conflate::List);
+        assert_eq!(cfg.conflate_for("/market/trades"), Conflate::List);
+        assert_eq!(cfg.conflate_for("/marketing"), Conflate::Latest);
+        cfg.conflate.insert("/".into(), Conflate::Count);
+        assert_eq!(cfg.conflate_for("/marketing"), Conflate::Count);
+    }
+
+    #[test]
+    fn parse_errors_have_a_location() {
+        let e = Config::parse(
+            "{\n  \"log_level\": \"Off\",\n  \"auth_mechanism\": \"Kerberoz\"\n}",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(e.contains("Kerberoz"), "{}", e);
+        assert!(e.contains("line 3 column"), "{}", e);
+        let e = Config::parse("{ \"log_level\": \"Off\", \"datetme\": \"local\" }")
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("unknown field `datetme`"), "{}", e);
+        assert!(e.contains("line 1 column"), "{}", e);
+        // nothing would ever be refreshed
+        let e = Config::parse("{ \"log_level\": \"Off\", \"max_refresh_batch\": 0 }")
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("integer `0`"), "{}", e);
+        let cfg = Config::parse("{ \"log_level\": \"Info\" }").unwrap();
+        assert_eq!(cfg.log_level, LevelFilter::Info);
+        assert_eq!(cfg.load_error, None);
+    }
+
+    #[test]
+    fn reload_keeps_working_settings() {
+        let path = std::env::temp_dir()
+            .join(format!("netidx-excel-reload-{}.json", std::process::id()));
+        let current = Config { publish_base: Some("/excel".into()), ..Config::default() };
+        fs::write(&path, r#"{ "log_level": "Debug", "auth_mechanism": "Anonymous" }"#)
+            .unwrap();
+        let cfg = reload(&path, &current);
+        assert_eq!(cfg.log_level, LevelFilter::Debug);
+        assert_eq!(cfg.auth_mechanism, Some(Auth::Anonymous));
+        assert_eq!(cfg.publish_base, None);
+        assert_eq!(log_level(&cfg), LevelFilter::Debug);
+        fs::write(&path, r#"{ "log_level": "Debug", "#).unwrap();
+        let cfg = reload(&path, &current);
+        assert_eq!(cfg.publish_base, Some("/excel".into()));
+        assert!(cfg.load_error.unwrap().contains("line 1 column"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_creates_a_default() {
+        let dir = std::env::temp_dir()
+            .join(format!("netidx-excel-load-{}", std::process::id()));
+        let path = dir.join("config.json");
+        let cfg = load_config(&path);
+        assert_eq!(cfg.load_error, None);
+        assert_eq!(cfg.log_level, Config::default().log_level);
+        assert_eq!(load_config(&path).load_error, None);
+        fs::write(&path, r#"{ "log_level": "Debug", "#).unwrap();
+        let cfg = load_config(&path);
+        assert_eq!(cfg.log_level, Config::default().log_level);
+        assert!(cfg.load_error.unwrap().contains("line 1 column"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn netidx_config() {
+        let cfg = Config::parse(
+            r#"{
+                "log_level": "Off",
+                "resolver": { "base": "/", "addrs": [["127.0.0.1:4564", "Anonymous"]] }
+            }"#,
+        )
+        .unwrap();
+        let netidx = cfg.netidx().unwrap();
+        assert_eq!(netidx.addrs[0].0, "127.0.0.1:4564".parse().unwrap());
+        let path = std::env::temp_dir()
+            .join(format!("netidx-excel-client-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{ "base": "/", "addrs": [["127.0.0.1:4565", "Anonymous"]] }"#,
+        )
+        .unwrap();
+        let cfg = Config { netidx_config: Some(path.clone()), ..Config::default() };
+        assert_eq!(cfg.netidx().unwrap().addrs[0].0, "127.0.0.1:4565".parse().unwrap());
+        assert!(cfg.netidx_changed(&Config::default()));
+        fs::remove_file(&path).unwrap();
+        let e = Config::parse(
+            r#"{ "log_level": "Off", "netidx_config": "c.json", "resolver": {} }"#,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("can't both be set"), "{}", e);
+        let e = Config::parse(
+            r#"{
+                "log_level": "Off",
+                "profiles": { "uat": { "netidx_config": "c.json", "resolver": {} } }
+            }"#,
+        )
+        .unwrap_err();
+        assert!(e.to_string().starts_with("profile uat:"), "{}", e);
+    }
+}
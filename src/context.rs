@@ -0,0 +1,185 @@
+use crate::config::{self, Auth};
+use anyhow::Result;
+use log::debug;
+use netidx::{
+    config::Config,
+    subscriber::{DesiredAuth, Subscriber},
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::runtime::{Handle, Runtime};
+
+/// The async runtime and netidx subscriber that servers run on
+pub(crate) struct Context {
+    runtime: Option<Runtime>,
+    /// the config this context was built from
+    built_from: config::Config,
+    pub(crate) config: Config,
+    pub(crate) auth: DesiredAuth,
+    pub(crate) subscriber: Subscriber,
+    /// subscribers for the profiles topics have asked for so far
+    profiles: Mutex<Vec<(config::Profile, Subscriber)>>,
+}
+
+// Excel may create more than one server, e.g. when it restarts the RTD
+// server, or when VBA creates one. They all share one context, which lives
+// as long as at least one of them does. When the config changes the
+// servers that are reloaded move to a new shared context, and the old one
+// goes away with the last of the others.
+static SHARED: Lazy<Mutex<Weak<Context>>> = Lazy::new(|| Mutex::new(Weak::new()));
+
+fn shared_with(
+    reuse: impl FnOnce(&Context) -> bool,
+    init: impl FnOnce() -> Result<Context>,
+) -> Result<Arc<Context>> {
+    let mut shared = SHARED.lock();
+    if let Some(ctx) = shared.upgrade() {
+        if reuse(&ctx) {
+            debug!("using the shared context");
+            return Ok(ctx);
+        }
+    }
+    let ctx = Arc::new(init()?);
+    *shared = Arc::downgrade(&ctx);
+    Ok(ctx)
+}
+
+fn desired_auth(auth: Option<Auth>, config: &Config) -> DesiredAuth {
+    match auth {
+        None => config.default_auth(),
+        Some(Auth::Anonymous) => DesiredAuth::Anonymous,
+        Some(Auth::Kerberos) => DesiredAuth::Krb5 { upn: None, spn: None },
+        Some(Auth::Tls) => DesiredAuth::Tls { identity: None },
+    }
+}
+
+impl Context {
+    /// Build a new context. If `netidx_cfg` isn't specified then the
+    /// netidx config `cfg` asks for is loaded.
+    pub(crate) fn new(cfg: &config::Config, netidx_cfg: Option<Config>) -> Result<Self> {
+        debug!("init runtime");
+        let runtime = Runtime::new()?;
+        debug!("entering async to init subscriber");
+        let (config, auth, subscriber) = runtime.block_on(async {
+            debug!("running in async context");
+            let config = match netidx_cfg {
+                Some(config) => config,
+                None => {
+                    debug!("loading config file");
+                    cfg.netidx()?
+                }
+            };
+            let auth = desired_auth(cfg.auth_mechanism, &config);
+            debug!("starting subscriber");
+            let subscriber = Subscriber::new(config.clone(), auth.clone())?;
+            Ok::<_, anyhow::Error>((config, auth, subscriber))
+        })?;
+        Ok(Context {
+            runtime: Some(runtime),
+            built_from: cfg.clone(),
+            config,
+            auth,
+            subscriber,
+            profiles: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The context shared by every server in the process that uses the
+    /// same netidx settings. It's created when the first one starts, and
+    /// shut down when the last one goes away.
+    pub(crate) fn shared(cfg: &config::Config) -> Result<Arc<Self>> {
+        shared_with(|ctx| !ctx.built_from.netidx_changed(cfg), || Context::new(cfg, None))
+    }
+
+    /// the subscriber for `profile`, running on this context's runtime.
+    /// It's started the first time it's asked for, and kept as long as
+    /// the context is, so reloads that don't change the profile reuse it.
+    pub(crate) fn profile_subscriber(
+        &self,
+        profile: &config::Profile,
+    ) -> Result<Subscriber> {
+        let mut profiles = self.profiles.lock();
+        if let Some((_, subscriber)) = profiles.iter().find(|(p, _)| p == profile) {
+            return Ok(subscriber.clone());
+        }
+        let config = profile.netidx()?;
+        let auth = desired_auth(profile.auth_mechanism, &config);
+        let subscriber = {
+            let _guard = self.runtime().enter();
+            Subscriber::new(config, auth)?
+        };
+        profiles.push((profile.clone(), subscriber.clone()));
+        Ok(subscriber)
+    }
+
+    pub(crate) fn runtime(&self) -> &Runtime {
+        self.runtime.as_ref().unwrap()
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            debug!("shutting down runtime");
+            // the last server may go away in a task running on this
+            // runtime, in which case we can't wait for it to stop
+            if Handle::try_current().is_ok() {
+                runtime.shutdown_background()
+            } else {
+                runtime.shutdown_timeout(Duration::from_secs(1))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local() -> Result<Context> {
+        let netidx_cfg = Config::parse(
+            r#"{ "base": "/", "addrs": [["127.0.0.1:4564", "Anonymous"]] }"#,
+        )?;
+        Context::new(&config::Config::default(), Some(netidx_cfg))
+    }
+
+    #[test]
+    fn shared_until_the_last_reference_is_gone() {
+        let a = shared_with(|_| true, local).unwrap();
+        let b = shared_with(|_| true, || panic!("should have been shared")).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        drop(a);
+        assert!(SHARED.lock().upgrade().is_some());
+        drop(b);
+        assert!(SHARED.lock().upgrade().is_none());
+        let c = shared_with(|_| true, local).unwrap();
+        assert_eq!(Arc::strong_count(&c), 1);
+        // a context that doesn't match the config is replaced, but lives
+        // on for whoever still has it
+        let d = shared_with(|_| false, local).unwrap();
+        assert!(!Arc::ptr_eq(&c, &d));
+        let e = shared_with(|_| true, || panic!("should have been shared")).unwrap();
+        assert!(Arc::ptr_eq(&d, &e));
+    }
+
+    #[test]
+    fn profile_subscribers_are_reused() {
+        let ctx = local().unwrap();
+        let profile = |port: u16| config::Profile {
+            resolver: Some(serde_json::json!({
+                "base": "/",
+                "addrs": [[format!("127.0.0.1:{}", port), "Anonymous"]]
+            })),
+            ..config::Profile::default()
+        };
+        ctx.profile_subscriber(&profile(4565)).unwrap();
+        ctx.profile_subscriber(&profile(4565)).unwrap();
+        assert_eq!(ctx.profiles.lock().len(), 1);
+        ctx.profile_subscriber(&profile(4566)).unwrap();
+        assert_eq!(ctx.profiles.lock().len(), 2);
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::Result;
+use log::error;
+use std::{
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+    sync::mpsc,
+};
+
+/// Tells the host that there is new data to collect with
+/// `Server::refresh_data`. For Excel this is `IRTDUpdateEvent::UpdateNotify`.
+///
+/// `notify` is called from the server's background tasks as well as from
+/// its methods, with the lock guarding the notifier held, so it must not
+/// block and must not call back into the server. Calling `refresh_data`
+/// from `notify` deadlocks, the host should call it from its own thread.
+pub trait Notify: Send + 'static {
+    fn notify(&self);
+}
+
+/// A notification is a message on the channel, e.g. for tests
+impl Notify for mpsc::Sender<()> {
+    fn notify(&self) {
+        let _ = self.send(());
+    }
+}
+
+/// Notify a host in another process by sending it an empty datagram
+#[derive(Debug)]
+pub struct UdpNotify(UdpSocket);
+
+impl UdpNotify {
+    /// send notifications to `addr`
+    pub fn new(addr: impl ToSocketAddrs) -> Result<Self> {
+        let socket = UdpSocket::bind(("127.0.0.1", 0))?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpNotify(socket))
+    }
+}
+
+impl Notify for UdpNotify {
+    fn notify(&self) {
+        match self.0.send(&[]) {
+            Ok(_) => (),
+            // a notification is already on its way, that's enough
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => error!("udp notify failed {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn channel() {
+        let (tx, rx) = mpsc::channel();
+        let n: Box<dyn Notify> = Box::new(tx);
+        n.notify();
+        assert_eq!(rx.try_recv(), Ok(()))
+    }
+
+    #[test]
+    fn udp() {
+        let host = UdpSocket::bind("127.0.0.1:0").unwrap();
+        host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let n = UdpNotify::new(host.local_addr().unwrap()).unwrap();
+        n.notify();
+        let mut buf = [0u8; 1];
+        assert_eq!(host.recv(&mut buf).unwrap(), 0)
+    }
+}
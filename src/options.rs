@@ -0,0 +1,382 @@
+use anyhow::{bail, Error, Result};
+use chrono_tz::Tz;
+use netidx::{chars::Chars, subscriber::Value};
+use once_cell::sync::Lazy;
+use std::{borrow::Cow, fmt, str::FromStr, sync::Arc};
+
+/// How DateTime values are shown
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum DateTimeFormat {
+    /// an Excel date in UTC
+    #[default]
+    Utc,
+    /// an Excel date in the local time zone
+    Local,
+    /// an Excel date in the named time zone, e.g. America/New_York
+    Zone(Tz),
+    /// as text, e.g. "2022-03-01 12:00:00 UTC"
+    Text,
+}
+
+impl FromStr for DateTimeFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "utc" => Ok(DateTimeFormat::Utc),
+            "local" => Ok(DateTimeFormat::Local),
+            "text" => Ok(DateTimeFormat::Text),
+            s => match s.parse::<Tz>() {
+                Ok(tz) => Ok(DateTimeFormat::Zone(tz)),
+                Err(_) => bail!(
+                    "invalid datetime {}, expected utc, local, text or a time zone",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for DateTimeFormat {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for DateTimeFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DateTimeFormat::Utc => write!(f, "utc"),
+            DateTimeFormat::Local => write!(f, "local"),
+            DateTimeFormat::Zone(tz) => write!(f, "{}", tz.name()),
+            DateTimeFormat::Text => write!(f, "text"),
+        }
+    }
+}
+
+impl From<DateTimeFormat> for String {
+    fn from(f: DateTimeFormat) -> Self {
+        f.to_string()
+    }
+}
+
+/// How Duration values are shown
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DurationFormat {
+    /// a number of seconds
+    #[default]
+    Seconds,
+    /// a fraction of a day, so the [h]:mm:ss number format works
+    Days,
+    /// as text, e.g. "12.5s"
+    Text,
+}
+
+impl FromStr for DurationFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "seconds" => Ok(DurationFormat::Seconds),
+            "days" => Ok(DurationFormat::Days),
+            "text" => Ok(DurationFormat::Text),
+            s => bail!("invalid duration {}, expected seconds, days or text", s),
+        }
+    }
+}
+
+/// How Decimal values are shown
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecimalFormat {
+    /// exactly, as a VT_DECIMAL
+    #[default]
+    Exact,
+    /// as currency, rounded to 4 decimal places. Values too big for
+    /// currency are shown exactly.
+    Currency,
+    /// as a floating point number, which may lose precision
+    F64,
+}
+
+impl FromStr for DecimalFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "exact" => Ok(DecimalFormat::Exact),
+            "currency" => Ok(DecimalFormat::Currency),
+            "f64" => Ok(DecimalFormat::F64),
+            s => bail!("invalid decimal {}, expected exact, currency or f64", s),
+        }
+    }
+}
+
+/// What a topic shows when a path updates more than once between two
+/// RefreshData calls
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Conflate {
+    /// the latest value
+    #[default]
+    Latest,
+    /// the first value since the last refresh
+    First,
+    /// the number of updates since the last refresh
+    Count,
+    /// an array of every value since the last refresh
+    List,
+}
+
+impl FromStr for Conflate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "latest" => Ok(Conflate::Latest),
+            "first" => Ok(Conflate::First),
+            "count" => Ok(Conflate::Count),
+            "list" => Ok(Conflate::List),
+            s => bail!("invalid conflate {}, expected latest, first, count or list", s),
+        }
+    }
+}
+
+/// How errors are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Errors {
+    /// as Excel error values, so ISERROR and IFERROR work
+    Excel,
+    /// as text, e.g. "#ERR no such path"
+    Text,
+    /// just the error message, and an empty cell if there is no error
+    Message,
+    /// as an empty cell
+    Blank,
+    /// as the default value, or an empty cell if there isn't one
+    Default,
+}
+
+impl FromStr for Errors {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "excel" => Ok(Errors::Excel),
+            "text" => Ok(Errors::Text),
+            "message" => Ok(Errors::Message),
+            "blank" => Ok(Errors::Blank),
+            "default" => Ok(Errors::Default),
+            s => bail!(
+                "invalid errors {}, expected excel, text, message, blank or default",
+                s
+            ),
+        }
+    }
+}
+
+/// How values are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// numbers as numbers, strings as strings, etc
+    Auto,
+    /// everything as text
+    Text,
+}
+
+/// Per topic options, given as "option=value" in the topic strings
+/// following the path.
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+    pub(crate) default: Option<Value>,
+    pub(crate) format: Format,
+    pub(crate) scale: Option<f64>,
+    pub(crate) errors: Errors,
+    /// if not specified the datetime format in the config file is used
+    pub(crate) datetime: Option<DateTimeFormat>,
+    /// if not specified the duration format in the config file is used
+    pub(crate) duration: Option<DurationFormat>,
+    /// if not specified the decimal format in the config file is used
+    pub(crate) decimal: Option<DecimalFormat>,
+    /// show just this element of an array, e.g. [1][0]
+    pub(crate) index: Option<Vec<usize>>,
+    /// if not specified the conflate setting for the path in the config
+    /// file is used
+    pub(crate) conflate: Option<Conflate>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            default: None,
+            format: Format::Auto,
+            scale: None,
+            errors: Errors::Excel,
+            datetime: None,
+            duration: None,
+            decimal: None,
+            index: None,
+            conflate: None,
+        }
+    }
+}
+
+pub(crate) static DEFAULT: Lazy<Arc<Options>> =
+    Lazy::new(|| Arc::new(Options::default()));
+
+/// Excel hands us every topic as a string, so values are parsed as a
+/// netidx value literal, and if that fails they are taken as a string.
+pub(crate) fn parse_value(s: String) -> Value {
+    match s.parse::<Value>() {
+        Ok(v) => v,
+        Err(_) => Value::String(Chars::from(s)),
+    }
+}
+
+fn parse_index(s: &str) -> Result<Vec<usize>> {
+    let mut res = vec![];
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let (i, tl) = match rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+            Some(r) => r,
+            None => bail!("invalid index {}, expected e.g. [1][0]", s),
+        };
+        match i.trim().parse::<usize>() {
+            Ok(i) => res.push(i),
+            Err(_) => bail!("invalid index {}, expected e.g. [1][0]", s),
+        }
+        rest = tl.trim_start();
+    }
+    if res.is_empty() {
+        bail!("empty index")
+    }
+    Ok(res)
+}
+
+fn format_index(idx: &[usize]) -> String {
+    idx.iter().map(|i| format!("[{}]", i)).collect()
+}
+
+fn select<'a>(v: &'a Value, idx: &[usize]) -> Cow<'a, Value> {
+    let mut cur = v;
+    for (n, i) in idx.iter().enumerate() {
+        match cur {
+            Value::Null | Value::Error(_) => break,
+            Value::Array(a) if *i < a.len() => cur = &a[*i],
+            _ => {
+                let e = format!("no element {}", format_index(&idx[..=n]));
+                return Cow::Owned(Value::Error(Chars::from(e)));
+            }
+        }
+    }
+    Cow::Borrowed(cur)
+}
+
+fn as_f64(v: &Value) -> Option<f64> {
+    match v {
+        Value::I32(v) | Value::Z32(v) => Some(*v as f64),
+        Value::U32(v) | Value::V32(v) => Some(*v as f64),
+        Value::I64(v) | Value::Z64(v) => Some(*v as f64),
+        Value::U64(v) | Value::V64(v) => Some(*v as f64),
+        Value::F32(v) => Some(*v as f64),
+        Value::F64(v) => Some(*v),
+        Value::Decimal(d) => f64::try_from(*d).ok(),
+        _ => None,
+    }
+}
+
+impl Options {
+    pub(crate) fn parse<S: AsRef<str>>(
+        opts: impl IntoIterator<Item = S>,
+    ) -> Result<Self> {
+        let mut res = Options::default();
+        for opt in opts {
+            let opt = opt.as_ref();
+            if opt.trim_start().starts_with('[') {
+                res.index = Some(parse_index(opt)?);
+                continue;
+            }
+            let (k, v) = match opt.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => bail!("expected option=value, got {}", opt),
+            };
+            match k {
+                "default" => res.default = Some(parse_value(v.into())),
+                "format" => {
+                    res.format = match v {
+                        "auto" => Format::Auto,
+                        "text" => Format::Text,
+                        v => bail!("invalid format {}, expected auto or text", v),
+                    }
+                }
+                "scale" => match v.parse::<f64>() {
+                    Ok(f) => res.scale = Some(f),
+                    Err(_) => bail!("invalid scale {}, expected a number", v),
+                },
+                "errors" => res.errors = v.parse()?,
+                "datetime" => res.datetime = Some(v.parse()?),
+                "duration" => res.duration = Some(v.parse()?),
+                "decimal" => res.decimal = Some(v.parse()?),
+                "index" => res.index = Some(parse_index(v)?),
+                "conflate" => res.conflate = Some(v.parse()?),
+                k => bail!("unknown option {}", k),
+            }
+        }
+        Ok(res)
+    }
+
+    /// apply the index, default, error handling, scale and format options
+    /// to `v`
+    pub(crate) fn apply<'a>(&'a self, v: &'a Value) -> Cow<'a, Value> {
+        let v = match &self.index {
+            None => Cow::Borrowed(v),
+            Some(idx) => select(v, idx),
+        };
+        let msg = match &*v {
+            Value::Error(e) => Some(e.to_string()),
+            Value::Bytes(_) => Some(String::from("binary value")),
+            _ => None,
+        };
+        let v = match msg {
+            None if self.errors == Errors::Message => Cow::Owned(Value::Null),
+            None => {
+                let scaled = self.scale.and_then(|s| as_f64(&v).map(|f| f * s));
+                match (&self.default, scaled) {
+                    (Some(d), _) if matches!(&*v, Value::Null) => Cow::Borrowed(d),
+                    (_, Some(f)) => Cow::Owned(Value::F64(f)),
+                    (_, None) => v,
+                }
+            }
+            Some(msg) => match self.errors {
+                Errors::Excel => v,
+                Errors::Text => {
+                    let s = match &*v {
+                        Value::Bytes(_) => String::from("#BIN"),
+                        _ => format!("#ERR {}", msg),
+                    };
+                    Cow::Owned(Value::String(Chars::from(s)))
+                }
+                Errors::Message => Cow::Owned(Value::String(Chars::from(msg))),
+                Errors::Blank => Cow::Owned(Value::Null),
+                Errors::Default => match &self.default {
+                    Some(d) => Cow::Borrowed(d),
+                    None => Cow::Owned(Value::Null),
+                },
+            },
+        };
+        let text = self.format == Format::Text
+            && !matches!(
+                &*v,
+                Value::String(_) | Value::Null | Value::Error(_) | Value::Bytes(_)
+            );
+        if text {
+            Cow::Owned(Value::String(Chars::from(v.to_string())))
+        } else {
+            v
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use crate::{
+    cell::{cell_of_event, Cell},
+    notify::Notify,
+    options::{Conflate, Options},
+    server::TopicId,
+};
+use netidx::subscriber::{Event, Value};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    mem,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// the most values a list conflated topic will hold between refreshes,
+// after that the oldest are dropped.
+const MAX_LIST: usize = 10_000;
+
+enum Conflated {
+    Event(Arc<Event>),
+    Cell(Cell),
+    Count(u64),
+    List(VecDeque<Value>),
+}
+
+/// The state of one topic. The latest event is shared, so fanning an
+/// update out to many topics doesn't copy it.
+pub(crate) struct Slot {
+    pub(crate) tid: TopicId,
+    pub(crate) options: Arc<Options>,
+    conflate: Conflate,
+    value: Mutex<Option<Conflated>>,
+    dirty: AtomicBool,
+    live: AtomicBool,
+}
+
+impl Slot {
+    pub(crate) fn new(tid: TopicId, options: Arc<Options>) -> Arc<Self> {
+        Arc::new(Slot {
+            tid,
+            conflate: options.conflate.unwrap_or_default(),
+            options,
+            value: Mutex::new(None),
+            dirty: AtomicBool::new(false),
+            live: AtomicBool::new(true),
+        })
+    }
+
+    /// store `ev`, returns true if the slot needs to be queued
+    pub(crate) fn set(&self, ev: Arc<Event>) -> bool {
+        {
+            let mut value = self.value.lock();
+            match (self.conflate, &*ev, &mut *value) {
+                (Conflate::First, _, Some(_)) => (),
+                (Conflate::Latest | Conflate::First, _, _)
+                | (_, Event::Unsubscribed, _) => *value = Some(Conflated::Event(ev)),
+                (Conflate::Count, Event::Update(_), Some(Conflated::Count(n))) => *n += 1,
+                (Conflate::Count, Event::Update(_), _) => {
+                    *value = Some(Conflated::Count(1))
+                }
+                (Conflate::List, Event::Update(v), Some(Conflated::List(l))) => {
+                    if l.len() == MAX_LIST {
+                        l.pop_front();
+                    }
+                    l.push_back(v.clone())
+                }
+                (Conflate::List, Event::Update(v), _) => {
+                    *value = Some(Conflated::List(VecDeque::from([v.clone()])))
+                }
+            }
+        }
+        !self.dirty.swap(true, Ordering::AcqRel)
+    }
+
+    /// show `cell` as is, returns true if the slot needs to be queued
+    pub(crate) fn set_cell(&self, cell: Cell) -> bool {
+        *self.value.lock() = Some(Conflated::Cell(cell));
+        !self.dirty.swap(true, Ordering::AcqRel)
+    }
+
+    /// the topic was disconnected, anything still queued is dropped
+    pub(crate) fn close(&self) {
+        self.live.store(false, Ordering::Release);
+        *self.value.lock() = None;
+    }
+
+    // the flag must be cleared before the value is taken, otherwise an
+    // update landing in between would be neither delivered nor queued.
+    fn take(&self) -> Option<Cell> {
+        self.dirty.store(false, Ordering::Release);
+        let value = self.value.lock().take()?;
+        if !self.live.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(match value {
+            Conflated::Event(ev) => cell_of_event(&ev, &self.options),
+            Conflated::Cell(cell) => cell,
+            Conflated::Count(n) => {
+                cell_of_event(&Event::Update(Value::U64(n)), &self.options)
+            }
+            Conflated::List(l) => {
+                let v = Value::Array(Arc::from(Vec::from(l)));
+                cell_of_event(&Event::Update(v), &self.options)
+            }
+        })
+    }
+}
+
+/// Slots with events that Excel hasn't seen yet, in the order they became
+/// dirty. The locks here are only ever held to push or swap, never while
+/// processing a batch of updates, so RefreshData doesn't wait for the
+/// network.
+pub(crate) struct Pending {
+    queue: Mutex<VecDeque<Arc<Slot>>>,
+    notify: Mutex<Option<Box<dyn Notify>>>,
+    max_batch: Mutex<Option<NonZeroUsize>>,
+}
+
+impl Pending {
+    /// `max_batch` limits how many topics are taken at once
+    pub(crate) fn new(max_batch: Option<NonZeroUsize>) -> Self {
+        Pending {
+            queue: Mutex::new(VecDeque::new()),
+            notify: Mutex::new(None),
+            max_batch: Mutex::new(max_batch),
+        }
+    }
+
+    pub(crate) fn set_max_batch(&self, max_batch: Option<NonZeroUsize>) {
+        *self.max_batch.lock() = max_batch;
+    }
+
+    pub(crate) fn set_notify(&self, notify: Option<Box<dyn Notify>>) {
+        *self.notify.lock() = notify;
+    }
+
+    fn notify(&self) {
+        if let Some(notify) = &*self.notify.lock() {
+            notify.notify()
+        }
+    }
+
+    /// queue `slots`, that were just made dirty, and notify if there
+    /// was nothing pending before.
+    pub(crate) fn queue(&self, slots: &mut Vec<Arc<Slot>>) {
+        if !slots.is_empty() {
+            let was_empty = {
+                let mut queue = self.queue.lock();
+                let was_empty = queue.is_empty();
+                queue.extend(slots.drain(..));
+                was_empty
+            };
+            if was_empty {
+                self.notify()
+            }
+        }
+    }
+
+    /// set the value of one slot
+    pub(crate) fn push(&self, slot: &Arc<Slot>, ev: Event) {
+        if slot.set(Arc::new(ev)) {
+            self.queue(&mut vec![slot.clone()])
+        }
+    }
+
+    /// show `cell` in one slot
+    pub(crate) fn push_cell(&self, slot: &Arc<Slot>, cell: Cell) {
+        if slot.set_cell(cell) {
+            self.queue(&mut vec![slot.clone()])
+        }
+    }
+
+    /// take what changed since the last call, formatted for the cells it
+    /// will be shown in. If there are more than `max_batch` topics the
+    /// ones that have waited longest are taken, and the rest stay queued
+    /// for another refresh, which is requested before returning.
+    pub(crate) fn take(&self) -> Vec<(TopicId, Cell)> {
+        let (batch, more) = {
+            let mut queue = self.queue.lock();
+            match *self.max_batch.lock() {
+                None => (mem::take(&mut *queue), false),
+                Some(max) => {
+                    // closed slots are dropped on the way, they don't
+                    // count toward the batch
+                    let mut n = 0;
+                    let i = queue
+                        .iter()
+                        .position(|s| {
+                            if s.live.load(Ordering::Acquire) {
+                                n += 1
+                            }
+                            n > max.get()
+                        })
+                        .unwrap_or(queue.len());
+                    let batch = queue.drain(..i).collect::<VecDeque<_>>();
+                    (batch, !queue.is_empty())
+                }
+            }
+        };
+        if more {
+            self.notify()
+        }
+        batch.into_iter().filter_map(|slot| Some((slot.tid, slot.take()?))).collect()
+    }
+
+    /// the number of live topics waiting to be refreshed
+    pub(crate) fn len(&self) -> usize {
+        self.queue.lock().iter().filter(|s| s.live.load(Ordering::Acquire)).count()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.queue.lock().clear();
+        self.set_notify(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::ExcelError;
+    use std::sync::mpsc;
+
+    fn update(i: i64) -> Event {
+        Event::Update(Value::I64(i))
+    }
+
+    #[test]
+    fn coalesce_and_notify() {
+        let (tx, rx) = mpsc::channel();
+        let pending = Pending::new(None);
+        pending.set_notify(Some(Box::new(tx)));
+        let a = Slot::new(TopicId(0), Arc::new(Options::default()));
+        let b = Slot::new(TopicId(1), Arc::new(Options::default()));
+        for i in 0..10 {
+            pending.push(&a, update(i));
+            pending.push(&b, update(i * 2));
+        }
+        assert_eq!(rx.try_iter().count(), 1);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(
+            pending.take(),
+            vec![(TopicId(0), Cell::I64(9)), (TopicId(1), Cell::I64(18))]
+        );
+        assert_eq!(pending.take(), vec![]);
+        pending.push(&b, update(42));
+        assert_eq!(rx.try_iter().count(), 1);
+        assert_eq!(pending.take(), vec![(TopicId(1), Cell::I64(42))]);
+    }
+
+    #[test]
+    fn shared_events() {
+        let pending = Pending::new(None);
+        let slots = (0..3)
+            .map(|i| Slot::new(TopicId(i), Arc::new(Options::default())))
+            .collect::<Vec<_>>();
+        let ev = Arc::new(update(1));
+        let mut dirty =
+            slots.iter().filter(|s| s.set(ev.clone())).cloned().collect::<Vec<_>>();
+        assert_eq!(Arc::strong_count(&ev), 4);
+        pending.queue(&mut dirty);
+        slots[1].close();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(
+            pending.take(),
+            vec![(TopicId(0), Cell::I64(1)), (TopicId(2), Cell::I64(1))]
+        );
+        assert_eq!(Arc::strong_count(&ev), 1);
+    }
+
+    #[test]
+    fn conflate() {
+        let pending = Pending::new(None);
+        let slot = |i, c| {
+            let options = Options { conflate: Some(c), ..Options::default() };
+            Slot::new(TopicId(i), Arc::new(options))
+        };
+        let slots = [
+            slot(0, Conflate::Latest),
+            slot(1, Conflate::First),
+            slot(2, Conflate::Count),
+            slot(3, Conflate::List),
+        ];
+        for i in 1..=3 {
+            for slot in &slots {
+                pending.push(slot, update(i))
+            }
+        }
+        let list =
+            Value::Array(Arc::from(vec![Value::I64(1), Value::I64(2), Value::I64(3)]));
+        assert_eq!(
+            pending.take(),
+            vec![
+                (TopicId(0), Cell::I64(3)),
+                (TopicId(1), Cell::I64(1)),
+                (TopicId(2), Cell::U64(3)),
+                (TopicId(3), Cell::String(list.to_string().into())),
+            ]
+        );
+        // everything starts over after a refresh
+        for slot in &slots {
+            pending.push(slot, update(4))
+        }
+        pending.push(&slots[2], Event::Unsubscribed);
+        let taken = pending.take();
+        assert_eq!(taken[1], (TopicId(1), Cell::I64(4)));
+        assert_eq!(taken[2], (TopicId(2), Cell::Error(ExcelError::NA)));
+        let list = Value::Array(Arc::from(vec![Value::I64(4)]));
+        assert_eq!(taken[3], (TopicId(3), Cell::String(list.to_string().into())));
+    }
+
+    #[test]
+    fn max_batch() {
+        let (tx, rx) = mpsc::channel();
+        let pending = Pending::new(NonZeroUsize::new(2));
+        pending.set_notify(Some(Box::new(tx)));
+        let slots = (0..5)
+            .map(|i| Slot::new(TopicId(i), Arc::new(Options::default())))
+            .collect::<Vec<_>>();
+        for slot in &slots {
+            pending.push(slot, update(1))
+        }
+        slots[1].close();
+        assert_eq!(rx.try_iter().count(), 1);
+        assert_eq!(
+            pending.take(),
+            vec![(TopicId(0), Cell::I64(1)), (TopicId(2), Cell::I64(1))]
+        );
+        // the leftovers asked for another refresh, and keep their place
+        // ahead of topics that became dirty later
+        assert_eq!(rx.try_iter().count(), 1);
+        pending.push(&slots[0], update(2));
+        pending.push(&slots[3], update(3));
+        assert_eq!(
+            pending.take(),
+            vec![(TopicId(3), Cell::I64(3)), (TopicId(4), Cell::I64(1))]
+        );
+        assert_eq!(rx.try_iter().count(), 1);
+        assert_eq!(pending.take(), vec![(TopicId(0), Cell::I64(2))]);
+        assert_eq!(rx.try_iter().count(), 0);
+        assert_eq!(pending.take(), vec![]);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{config, server::TopicId};
+use anyhow::{anyhow, Result};
+use futures::prelude::*;
+use fxhash::{FxHashMap, FxHashSet};
+use log::debug;
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    publisher::{BindCfg, Publisher, PublisherBuilder, Val},
+    subscriber::{DesiredAuth, Value},
+};
+use std::{collections::hash_map::Entry, mem, sync::Arc};
+use tokio::runtime::Runtime;
+
+struct Published {
+    val: Val,
+    topics: FxHashSet<TopicId>,
+}
+
+/// Values pushed from the workbook, published under the configured
+/// base path. The publisher is started the first time something is
+/// published, and a path stays published as long as at least one topic
+/// refers to it.
+pub(crate) struct Publish {
+    config: Config,
+    auth: DesiredAuth,
+    base: Option<Path>,
+    bind: Option<String>,
+    publisher: Option<Publisher>,
+    /// identifies the start in progress, if there is one
+    starting: Option<Arc<()>>,
+    /// what to publish once the publisher has started
+    queued: FxHashMap<TopicId, (Path, Value)>,
+    by_path: FxHashMap<Path, Published>,
+    by_topic: FxHashMap<TopicId, Path>,
+}
+
+impl Publish {
+    pub(crate) fn new(cfg: &config::Config, config: Config, auth: DesiredAuth) -> Self {
+        Publish {
+            config,
+            auth,
+            base: cfg.publish_base.as_ref().map(|b| Path::from(b.clone())),
+            bind: cfg.publish_bind.clone(),
+            publisher: None,
+            starting: None,
+            queued: FxHashMap::default(),
+            by_path: FxHashMap::default(),
+            by_topic: FxHashMap::default(),
+        }
+    }
+
+    /// Start the publisher, unless it is started or starting already.
+    /// Starting it talks to the resolver, and maybe the KDC, so it is
+    /// done in the background, and the result handed to `ready` with the
+    /// token returned here.
+    pub(crate) fn start(
+        &mut self,
+    ) -> Option<(Arc<()>, impl Future<Output = Result<Publisher>> + Send + 'static)> {
+        if self.publisher.is_some() || self.starting.is_some() {
+            return None;
+        }
+        debug!("starting publisher");
+        let token = Arc::new(());
+        self.starting = Some(token.clone());
+        let config = self.config.clone();
+        let auth = self.auth.clone();
+        let bind = self.bind.clone();
+        Some((token, async move {
+            let mut builder = PublisherBuilder::new(config);
+            builder.desired_auth(auth);
+            if let Some(bind) = bind {
+                builder.bind_cfg(Some(bind.parse::<BindCfg>()?));
+            }
+            builder.build().await
+        }))
+    }
+
+    /// The publisher started, or failed to. Publishes what was queued
+    /// and returns the values the waiting topics should show.
+    pub(crate) fn ready(
+        &mut self,
+        runtime: &Runtime,
+        token: Arc<()>,
+        publisher: Result<Publisher, String>,
+    ) -> Vec<(TopicId, Value)> {
+        match &self.starting {
+            Some(t) if Arc::ptr_eq(t, &token) => self.starting = None,
+            // a publisher started for a config that has been replaced
+            Some(_) | None => return vec![],
+        }
+        let queued = mem::take(&mut self.queued);
+        match publisher {
+            Err(e) => {
+                let e = Chars::from(format!("could not start the publisher {}", e));
+                queued.into_keys().map(|tid| (tid, Value::Error(e.clone()))).collect()
+            }
+            Ok(publisher) => {
+                self.publisher = Some(publisher.clone());
+                queued
+                    .into_iter()
+                    .map(|(tid, (path, v))| {
+                        match self.publish_now(runtime, &publisher, tid, path, v.clone())
+                        {
+                            Ok(()) => (tid, v),
+                            Err(e) => (tid, Value::Error(Chars::from(e.to_string()))),
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// publish `v` at `name` under the base path on behalf of `tid`,
+    /// or update it if it's already published. Returns false if it was
+    /// queued because the publisher hasn't started yet.
+    pub(crate) fn publish(
+        &mut self,
+        runtime: &Runtime,
+        tid: TopicId,
+        name: &str,
+        v: Value,
+    ) -> Result<bool> {
+        let base = self
+            .base
+            .as_ref()
+            .ok_or_else(|| anyhow!("publish_base is not configured"))?;
+        let path = base.append(name);
+        match self.publisher.clone() {
+            None => {
+                self.unpublish(tid);
+                self.queued.insert(tid, (path, v));
+                Ok(false)
+            }
+            Some(publisher) => {
+                self.publish_now(runtime, &publisher, tid, path, v)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn publish_now(
+        &mut self,
+        runtime: &Runtime,
+        publisher: &Publisher,
+        tid: TopicId,
+        path: Path,
+        v: Value,
+    ) -> Result<()> {
+        self.unpublish(tid);
+        match self.by_path.entry(path.clone()) {
+            Entry::Occupied(mut e) => {
+                let published = e.get_mut();
+                let mut batch = publisher.start_batch();
+                published.val.update(&mut batch, v);
+                runtime.spawn(batch.commit(None));
+                published.topics.insert(tid);
+            }
+            Entry::Vacant(e) => {
+                debug!("publishing {}", path);
+                let val = publisher.publish(path.clone(), v)?;
+                let mut topics = FxHashSet::default();
+                topics.insert(tid);
+                e.insert(Published { val, topics });
+            }
+        }
+        self.by_topic.insert(tid, path);
+        Ok(())
+    }
+
+    /// release `tid`, if it was the last topic referring to its path then
+    /// the path is unpublished.
+    pub(crate) fn unpublish(&mut self, tid: TopicId) {
+        self.queued.remove(&tid);
+        if let Some(path) = self.by_topic.remove(&tid) {
+            if let Entry::Occupied(mut e) = self.by_path.entry(path) {
+                e.get_mut().topics.remove(&tid);
+                if e.get().topics.is_empty() {
+                    debug!("unpublishing {}", e.key());
+                    e.remove();
+                }
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.queued.clear();
+        self.by_topic.clear();
+        self.by_path.clear();
+    }
+}
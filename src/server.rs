@@ -1,56 +1,580 @@
-use crate::comglue::{self, dispatch::IRTDUpdateEventWrap};
-use anyhow::Result;
-use futures::{channel::mpsc, prelude::*};
+use crate::{
+    browse::{self, Browse, Query},
+    cell::{cell_of_init_error, cell_of_topic_error, Cell},
+    config::{self, Overflow},
+    context::Context,
+    notify::Notify,
+    options::{self, Conflate, Errors, Options},
+    pending::{Pending, Slot},
+    publisher::Publish,
+};
+use anyhow::{anyhow, Result};
+use futures::{channel::mpsc, future, prelude::*, select_biased};
 use fxhash::{FxBuildHasher, FxHashMap, FxHashSet};
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use netidx::{
+    chars::Chars,
     config::Config,
     path::Path,
-    pool::{Pool, Pooled},
-    subscriber::{DesiredAuth, Dval, Event, SubId, Subscriber, UpdatesFlags},
+    pool::Pooled,
+    publisher::Publisher,
+    subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags, Value},
 };
-use once_cell::sync::Lazy;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, HashMap, HashSet},
     default::Default,
     fmt, mem,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::Duration,
 };
-use tokio::runtime::Runtime;
+use tokio::{task::JoinHandle, time};
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
-pub(crate) struct TopicId(pub i32);
+pub struct TopicId(pub i32);
+
+// how long we wait for the publisher to acknowledge a write before we
+// report a timeout in the cell
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how long to wait before retrying a failed init, doubling each time
+const INIT_RETRY_MIN: Duration = Duration::from_secs(1);
+const INIT_RETRY_MAX: Duration = Duration::from_secs(60);
+
+type Init = Arc<dyn Fn(&config::Config) -> Result<Arc<Context>> + Send + Sync>;
+
+// batches of updates queued between the subscriber and the updates loop
+// if the config doesn't say
+const DEFAULT_CHANNEL_DEPTH: usize = 3;
+
+type Updates = Pooled<Vec<(SubId, Event)>>;
+
+enum Msg {
+    Updates(Updates),
+    /// updates for these subscriptions were dropped, their last values
+    /// must be read again
+    Reread(FxHashSet<SubId>),
+}
+
+/// One subscription, and the topics showing it
+struct Sub {
+    dval: Dval,
+    slots: FxHashMap<TopicId, Arc<Slot>>,
+}
+
+/// The part of the server on the path from the network to Excel. It is
+/// kept out of the ServerInner lock so that a large batch of updates
+/// doesn't hold up ConnectData or RefreshData.
+struct Shared {
+    by_id: RwLock<FxHashMap<SubId, Sub>>,
+    pending: Pending,
+    /// times the subscriber had to wait for the updates loop
+    blocked: AtomicU64,
+    /// batches of updates dropped because the updates loop was behind
+    dropped: AtomicU64,
+    config_status: Mutex<ConfigStatus>,
+    /// the topics showing blocked or dropped
+    counters: Mutex<FxHashMap<TopicId, (Counter, Arc<Slot>)>>,
+}
+
+/// How often the overflow policy had to be applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Counter {
+    Blocked,
+    Dropped,
+}
 
-static PENDING: Lazy<Pool<FxHashMap<TopicId, Event>>> =
-    Lazy::new(|| Pool::new(3, 1_000_000));
+/// The topics showing why the config file couldn't be loaded
+struct ConfigStatus {
+    error: Option<String>,
+    topics: FxHashMap<TopicId, Arc<Slot>>,
+}
+
+impl ConfigStatus {
+    fn value(&self) -> Value {
+        match &self.error {
+            None => Value::Null,
+            Some(e) => Value::String(Chars::from(e.clone())),
+        }
+    }
+}
+
+impl Shared {
+    fn new(cfg: &config::Config) -> Self {
+        Shared {
+            by_id: RwLock::new(HashMap::with_hasher(FxBuildHasher::default())),
+            pending: Pending::new(cfg.max_refresh_batch),
+            blocked: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            config_status: Mutex::new(ConfigStatus {
+                error: cfg.load_error.clone(),
+                topics: HashMap::with_hasher(FxBuildHasher::default()),
+            }),
+            counters: Mutex::new(HashMap::with_hasher(FxBuildHasher::default())),
+        }
+    }
+
+    fn counter(&self, counter: Counter) -> &AtomicU64 {
+        match counter {
+            Counter::Blocked => &self.blocked,
+            Counter::Dropped => &self.dropped,
+        }
+    }
+
+    // count one more, and show it in the topics watching the counter
+    fn count(&self, counter: Counter) {
+        let n = self.counter(counter).fetch_add(1, Ordering::Relaxed) + 1;
+        for (c, slot) in self.counters.lock().values() {
+            if *c == counter {
+                self.pending.push(slot, Event::Update(Value::U64(n)))
+            }
+        }
+    }
+
+    fn set_config_error(&self, error: Option<String>) {
+        let mut status = self.config_status.lock();
+        if status.error != error {
+            status.error = error;
+            for slot in status.topics.values() {
+                self.pending.push(slot, Event::Update(status.value()))
+            }
+        }
+    }
+
+    fn clear_status_topics(&self) {
+        for (_, slot) in self.config_status.lock().topics.drain() {
+            slot.close()
+        }
+        for (_, (_, slot)) in self.counters.lock().drain() {
+            slot.close()
+        }
+    }
+}
 
 struct ServerInner {
-    runtime: Runtime,
-    update: Option<IRTDUpdateEventWrap>,
-    subscriber: Subscriber,
-    updates: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
-    by_id: FxHashMap<SubId, FxHashSet<TopicId>>,
+    intake: JoinHandle<()>,
+    updates_loop: JoinHandle<()>,
+    updates: mpsc::Sender<Updates>,
+    slots: FxHashMap<TopicId, Arc<Slot>>,
+    /// what every topic asked for, to redo it if netidx is initialized
+    /// again
+    ops: FxHashMap<TopicId, Op>,
     by_topic: FxHashMap<TopicId, Dval>,
-    pending: Pooled<FxHashMap<TopicId, Event>>,
+    writes: FxHashMap<TopicId, Dval>,
+    publish: Publish,
+    browse: Browse,
+    cfg: config::Config,
+    /// the options of topics that aren't data topics, e.g. writes
+    defaults: Arc<Options>,
+    // dropped last, it might be the last reference to the runtime
+    ctx: Arc<Context>,
+}
+
+// the intake and updates loop would otherwise live as long as the
+// subscriptions they were registered with, and those may be shared with
+// other servers.
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.intake.abort();
+        self.updates_loop.abort()
+    }
+}
+
+// the formats a topic doesn't ask for come from the config
+fn resolve(cfg: &config::Config, options: &mut Options) {
+    options.datetime.get_or_insert(cfg.datetime);
+    options.duration.get_or_insert(cfg.duration);
+    options.decimal.get_or_insert(cfg.decimal);
+}
+
+fn defaults(cfg: &config::Config) -> Arc<Options> {
+    let mut options = Options::default();
+    resolve(cfg, &mut options);
+    Arc::new(options)
 }
 
 impl ServerInner {
-    fn clear(&mut self) {
-        self.update = None;
-        self.by_id.clear();
+    fn new(cfg: config::Config, ctx: Arc<Context>, shared: &Arc<Shared>) -> Self {
+        debug!("init updates channel");
+        let depth = cfg.update_channel_depth.unwrap_or(DEFAULT_CHANNEL_DEPTH);
+        let (tx, rx) = mpsc::channel(depth);
+        // no shared buffer, but a futures sender always has a slot of its
+        // own, and every subscription registers a clone of this one, so
+        // up to one batch per subscription can be queued for the intake.
+        // The overflow policy only applies past that, at the depth.
+        let (intake_tx, intake_rx) = mpsc::channel(0);
+        debug!("starting updates loop");
+        let updates_loop = ctx.runtime().spawn(updates_loop(shared.clone(), rx));
+        let intake = intake(shared.clone(), cfg.update_overflow, intake_rx, tx);
+        let intake = ctx.runtime().spawn(intake);
+        ServerInner {
+            intake,
+            updates_loop,
+            updates: intake_tx,
+            slots: HashMap::with_hasher(FxBuildHasher::default()),
+            ops: HashMap::with_hasher(FxBuildHasher::default()),
+            by_topic: HashMap::with_hasher(FxBuildHasher::default()),
+            writes: HashMap::with_hasher(FxBuildHasher::default()),
+            publish: Publish::new(&cfg, ctx.config.clone(), ctx.auth.clone()),
+            browse: Browse::new(),
+            defaults: defaults(&cfg),
+            cfg,
+            ctx,
+        }
+    }
+
+    fn clear(&mut self, shared: &Shared) {
+        self.close(shared);
+        self.ops.clear();
+        shared.blocked.store(0, Ordering::Relaxed);
+        shared.dropped.store(0, Ordering::Relaxed);
+        shared.pending.clear();
         self.by_topic.clear();
-        self.pending.clear();
+        self.writes.clear();
+        self.publish.clear();
+        self.browse.clear();
+    }
+
+    // stop delivering anything to Excel
+    fn close(&mut self, shared: &Shared) {
+        for (_, slot) in self.slots.drain() {
+            slot.close()
+        }
+        shared.by_id.write().clear();
+    }
+
+    fn push(&mut self, shared: &Shared, tid: TopicId, ev: Event) {
+        let slot = self
+            .slots
+            .entry(tid)
+            .or_insert_with(|| Slot::new(tid, self.defaults.clone()));
+        shared.pending.push(slot, ev)
+    }
+
+    /// use `cfg`, which must not need netidx initialized again. If the
+    /// formats or conflation changed the data topics are connected again
+    /// so they show the new ones.
+    fn set_cfg(&mut self, shared: &Shared, cfg: config::Config) {
+        let reformat = self.cfg.datetime != cfg.datetime
+            || self.cfg.duration != cfg.duration
+            || self.cfg.decimal != cfg.decimal
+            || self.cfg.conflate != cfg.conflate;
+        self.defaults = defaults(&cfg);
+        self.cfg = cfg;
+        if reformat {
+            let data = self
+                .ops
+                .iter()
+                .filter_map(|(tid, op)| match op {
+                    Op::Data(profile, path, options) => {
+                        Some((*tid, profile.clone(), path.clone(), options.clone()))
+                    }
+                    Op::Write(..) | Op::Publish(..) | Op::Browse(..) => None,
+                })
+                .collect::<Vec<_>>();
+            for (tid, profile, path, options) in data {
+                if let Err(e) =
+                    self.connect_data(shared, tid, &profile, path, options, true)
+                {
+                    error!("could not reconnect topic {}", e)
+                }
+            }
+        }
+    }
+
+    fn subscriber(&self, profile: &Option<String>) -> Result<Subscriber> {
+        let name = match profile {
+            None => return Ok(self.ctx.subscriber.clone()),
+            Some(name) => name,
+        };
+        let profile = self
+            .cfg
+            .profiles
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown profile {}", name))?;
+        debug!("subscriber for profile {}", name);
+        self.ctx.profile_subscriber(profile)
+    }
+
+    fn connect_data(
+        &mut self,
+        shared: &Shared,
+        tid: TopicId,
+        profile: &Option<String>,
+        path: Path,
+        mut options: Options,
+        resubscribe: bool,
+    ) -> Result<()> {
+        let subscriber = self.subscriber(profile)?;
+        if options.conflate.is_none() {
+            options.conflate = Some(self.cfg.conflate_for(&path));
+        }
+        resolve(&self.cfg, &mut options);
+        let slot = Slot::new(tid, Arc::new(options));
+        if let Some(old) = self.slots.insert(tid, slot.clone()) {
+            old.close()
+        }
+        // the subscriber dedups durable subscriptions, so every topic
+        // showing some part of this path shares one Dval, and the
+        // updates are fanned out to them through by_id. Subscription ids
+        // are unique in the process, so topics from every profile can
+        // share by_id.
+        let dv = subscriber.subscribe(path);
+        let mut by_id = shared.by_id.write();
+        match by_id.entry(dv.id()) {
+            Entry::Occupied(mut e) => {
+                // the subscription is already delivering updates to the
+                // other topics, this one only needs the last value. by_id
+                // stays locked until it has it, so an update can't be
+                // overwritten by an older last value.
+                e.get_mut().slots.insert(tid, slot.clone());
+                let last = dv.last();
+                let seeded = match &last {
+                    // the cell keeps the value it had until the new
+                    // subscription has one
+                    Event::Unsubscribed if resubscribe => false,
+                    _ => slot.set(Arc::new(last)),
+                };
+                drop(by_id);
+                if seeded {
+                    shared.pending.queue(&mut vec![slot])
+                }
+            }
+            Entry::Vacant(e) => {
+                let mut slots = HashMap::with_hasher(FxBuildHasher::default());
+                slots.insert(tid, slot.clone());
+                e.insert(Sub { dval: dv.clone(), slots });
+                drop(by_id);
+                // BEGIN_WITH_LAST delivers the last value, it must not be
+                // counted or accumulated twice. Only the first topic
+                // registers for updates, otherwise every topic already
+                // showing the path would get the last value again.
+                let last = dv.last();
+                match (slot.options.conflate, &last) {
+                    (Some(Conflate::Count | Conflate::List), Event::Update(_)) => (),
+                    (_, Event::Unsubscribed) if resubscribe => (),
+                    (_, _) => shared.pending.push(&slot, last),
+                }
+                dv.updates(UpdatesFlags::BEGIN_WITH_LAST, self.updates.clone());
+            }
+        }
+        self.by_topic.insert(tid, dv);
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        server: &Server,
+        tid: TopicId,
+        profile: &Option<String>,
+        path: Path,
+        v: Value,
+    ) -> Result<()> {
+        // the subscriber dedups durable subscriptions, so if this path is
+        // already shown in a cell we get the same Dval that is in by_topic,
+        // and the write goes over the existing connection.
+        let dv = self.subscriber(profile)?.subscribe(path);
+        let res = dv.write_with_recipt(v);
+        self.writes.insert(tid, dv);
+        let t = server.clone();
+        self.ctx.runtime().spawn(async move {
+            let v = match time::timeout(WRITE_TIMEOUT, res).await {
+                Ok(Ok(v)) => v,
+                Ok(Err(_)) => Value::Error(Chars::from("write failed")),
+                Err(_) => Value::Error(Chars::from("write timed out")),
+            };
+            t.write_result(tid, v)
+        });
+        Ok(())
+    }
+
+    fn publish(
+        &mut self,
+        server: &Server,
+        tid: TopicId,
+        name: &str,
+        v: Value,
+    ) -> Result<()> {
+        if self.publish.publish(self.ctx.runtime(), tid, name, v.clone())? {
+            self.push(&server.shared, tid, Event::Update(v));
+        } else if let Some((token, start)) = self.publish.start() {
+            let t = server.clone();
+            self.ctx.runtime().spawn(async move {
+                let publisher = start.await.map_err(|e| e.to_string());
+                t.publisher_ready(token, publisher)
+            });
+        }
+        Ok(())
+    }
+
+    fn browse(
+        &mut self,
+        server: &Server,
+        tid: TopicId,
+        profile: &Option<String>,
+        query: Query,
+        idx: Option<usize>,
+    ) -> Result<()> {
+        let resolver = self.subscriber(profile)?.resolver();
+        if let Some(token) = self.browse.add(tid, profile.clone(), query.clone(), idx) {
+            let profile = profile.clone();
+            // the query doesn't keep the server alive
+            let t = server.downgrade();
+            self.ctx.runtime().spawn(async move {
+                loop {
+                    let listing = browse::fetch(resolver.clone(), query.clone())
+                        .await
+                        .map_err(|e| e.to_string());
+                    let again = match t.upgrade() {
+                        None => false,
+                        Some(t) => t.browse_result(
+                            &token,
+                            profile.clone(),
+                            query.clone(),
+                            listing,
+                        ),
+                    };
+                    if !again {
+                        break;
+                    }
+                    time::sleep(browse::INTERVAL).await
+                }
+            });
+        } else if let Some(v) = self.browse.value(tid) {
+            self.push(&server.shared, tid, Event::Update(v));
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, server: &Server, tid: TopicId, op: Op) -> Result<()> {
+        self.ops.insert(tid, op.clone());
+        match op {
+            Op::Data(profile, path, options) => {
+                self.connect_data(&server.shared, tid, &profile, path, options, false)?
+            }
+            Op::Write(profile, path, v) => self.write(server, tid, &profile, path, v)?,
+            Op::Publish(name, v) => self.publish(server, tid, &name, v)?,
+            Op::Browse(profile, query, idx) => {
+                self.browse(server, tid, &profile, query, idx)?
+            }
+        }
+        Ok(())
     }
 }
 
+/// What a topic asked for, kept so it can be done again if netidx has
+/// to be initialized again, or done at all once netidx can be
+/// initialized
 #[derive(Clone)]
-pub struct Server(Arc<Mutex<Option<ServerInner>>>);
+enum Op {
+    Data(Option<String>, Path, Options),
+    Write(Option<String>, Path, Value),
+    Publish(String, Value),
+    Browse(Option<String>, Query, Option<usize>),
+}
+
+/// netidx could not be initialized. Topics connected in the meantime
+/// show the error, and wait here until a retry succeeds.
+struct Failed {
+    cfg: config::Config,
+    error: String,
+    waiting: FxHashMap<TopicId, (Arc<Slot>, Op)>,
+}
+
+impl Failed {
+    fn wait(&mut self, shared: &Shared, tid: TopicId, op: Op) {
+        let options = match &op {
+            Op::Data(_, _, options) => Arc::new(options.clone()),
+            Op::Write(..) | Op::Publish(..) | Op::Browse(..) => options::DEFAULT.clone(),
+        };
+        let slot = Slot::new(tid, options);
+        shared
+            .pending
+            .push_cell(&slot, cell_of_init_error(slot.options.errors, &self.error));
+        if let Some((old, _)) = self.waiting.insert(tid, (slot, op)) {
+            old.close()
+        }
+    }
+
+    fn set_error(&mut self, shared: &Shared, error: String) {
+        if error != self.error {
+            self.error = error;
+            for (slot, _) in self.waiting.values() {
+                let cell = cell_of_init_error(slot.options.errors, &self.error);
+                shared.pending.push_cell(slot, cell)
+            }
+        }
+    }
+
+    fn clear(&mut self, shared: &Shared) {
+        for (_, (slot, _)) in self.waiting.drain() {
+            slot.close()
+        }
+        shared.pending.clear();
+    }
+}
+
+enum State {
+    Running(Box<ServerInner>),
+    Failed(Failed),
+}
 
+/// What the server is keeping track of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// distinct subscriptions
+    pub subscriptions: usize,
+    /// topics connected to a subscription
+    pub topics: usize,
+    /// topics waiting for refresh_data
+    pub pending: usize,
+    /// times the subscriber waited because the updates loop was behind
+    pub blocked: u64,
+    /// batches of updates dropped because the updates loop was behind
+    pub dropped: u64,
+}
+
+#[derive(Clone)]
+pub struct Server {
+    inner: Arc<Mutex<State>>,
+    shared: Arc<Shared>,
+    init: Init,
+}
+
+struct WeakServer {
+    inner: Weak<Mutex<State>>,
+    shared: Weak<Shared>,
+    init: Init,
+}
+
+impl WeakServer {
+    fn upgrade(&self) -> Option<Server> {
+        Some(Server {
+            inner: self.inner.upgrade()?,
+            shared: self.shared.upgrade()?,
+            init: self.init.clone(),
+        })
+    }
+}
+
+/// A server using config.json, it follows changes to the file for as
+/// long as it lives
 impl Default for Server {
     fn default() -> Self {
-        let cfg = *comglue::CONFIG;
-        Self::new(cfg)
+        let server = Self::new((*config::current()).clone(), None);
+        let weak = server.downgrade();
+        config::on_reload(move |cfg| match weak.upgrade() {
+            None => false,
+            Some(server) => {
+                server.reload((**cfg).clone());
+                true
+            }
+        });
+        server
     }
 }
 
@@ -60,139 +584,525 @@ impl fmt::Debug for Server {
     }
 }
 
-impl Server {
-    async fn updates_loop(self, mut up: mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>) {
-        debug!("updates loop started");
-        while let Some(mut updates) = up.next().await {
-            let mut inner = self.0.lock();
-            if let Some(inner) = &mut *inner {
-                if let Some(update) = &mut inner.update {
-                    let call_update = inner.pending.is_empty();
-                    for (id, ev) in updates.drain(..) {
-                        if let Some(tids) = inner.by_id.get(&id) {
-                            let mut iter = tids.iter();
-                            for _ in 0..tids.len() - 1 {
-                                inner.pending.insert(*iter.next().unwrap(), ev.clone());
-                            }
-                            inner.pending.insert(*iter.next().unwrap(), ev);
+// sits between the subscriber and the updates loop, and applies the
+// overflow policy when the loop falls behind. Dropped updates are
+// remembered by subscription, and once there is room again the loop is
+// told to re-read their last values, so a cell can miss intermediate
+// values but never ends up stale.
+async fn intake(
+    shared: Arc<Shared>,
+    overflow: Overflow,
+    mut rx: mpsc::Receiver<Updates>,
+    mut tx: mpsc::Sender<Msg>,
+) {
+    enum Next {
+        Room(bool),
+        Updates(Option<Updates>),
+    }
+    let mut stale: FxHashSet<SubId> = HashSet::with_hasher(FxBuildHasher::default());
+    loop {
+        let next = if stale.is_empty() {
+            Next::Updates(rx.next().await)
+        } else {
+            let mut room = future::poll_fn(|cx| tx.poll_ready(cx)).fuse();
+            select_biased! {
+                r = room => Next::Room(r.is_ok()),
+                u = rx.next() => Next::Updates(u),
+            }
+        };
+        let updates = match next {
+            Next::Room(false) | Next::Updates(None) => break,
+            Next::Room(true) => match tx.start_send(Msg::Reread(mem::take(&mut stale))) {
+                Ok(()) => continue,
+                Err(_) => break,
+            },
+            Next::Updates(Some(updates)) => updates,
+        };
+        if !stale.is_empty() {
+            // still no room
+            shared.count(Counter::Dropped);
+            stale.extend(updates.iter().map(|(id, _)| *id));
+            continue;
+        }
+        match tx.try_send(Msg::Updates(updates)) {
+            Ok(()) => (),
+            Err(e) if e.is_disconnected() => break,
+            Err(e) => {
+                let msg = e.into_inner();
+                match overflow {
+                    Overflow::Block => {
+                        shared.count(Counter::Blocked);
+                        if tx.send(msg).await.is_err() {
+                            break;
                         }
                     }
-                    if call_update {
-                        debug!("calling update_notify");
-                        update.update_notify();
+                    Overflow::Drop => {
+                        shared.count(Counter::Dropped);
+                        if let Msg::Updates(updates) = msg {
+                            stale.extend(updates.iter().map(|(id, _)| *id))
+                        }
                     }
                 }
             }
         }
-        debug!("updates loop terminated")
     }
+    debug!("intake terminated")
+}
 
-    pub(crate) fn new(cfg: comglue::Config) -> Server {
-        macro_rules! or_err {
-            ($e:expr, $msg:expr) => {
-                match $e {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!($msg, e);
-                        return Server(Arc::new(Mutex::new(None)));
+async fn updates_loop(shared: Arc<Shared>, mut up: mpsc::Receiver<Msg>) {
+    debug!("updates loop started");
+    let mut dirty = vec![];
+    while let Some(msg) = up.next().await {
+        let mut set = |sub: &Sub, ev: Arc<Event>| {
+            for slot in sub.slots.values() {
+                if slot.set(ev.clone()) {
+                    dirty.push(slot.clone())
+                }
+            }
+        };
+        // by_id is only locked while one subscription is fanned out, so
+        // ConnectData and DisconnectData never wait for a whole batch
+        match msg {
+            Msg::Updates(mut updates) => {
+                for (id, ev) in updates.drain(..) {
+                    if let Some(sub) = shared.by_id.read().get(&id) {
+                        set(sub, Arc::new(ev))
                     }
                 }
-            };
+            }
+            Msg::Reread(ids) => {
+                for id in ids {
+                    if let Some(sub) = shared.by_id.read().get(&id) {
+                        set(sub, Arc::new(sub.dval.last()))
+                    }
+                }
+            }
         }
-        debug!("init runtime");
-        let runtime = or_err!(Runtime::new(), "could not init async runtime {}");
-        debug!("entering async to init subscriber");
-        let subscriber: Result<Subscriber> = runtime.block_on(async {
-            debug!("running in async context");
-            debug!("loading config file");
-            let config = Config::load_default()?;
-            let auth = match cfg.auth_mechanism {
-                None => config.default_auth(),
-                Some(comglue::Auth::Anonymous) => DesiredAuth::Anonymous,
-                Some(comglue::Auth::Kerberos) => {
-                    DesiredAuth::Krb5 { upn: None, spn: None }
-                }
-                Some(comglue::Auth::Tls) => DesiredAuth::Tls { identity: None },
+        shared.pending.queue(&mut dirty);
+    }
+    debug!("updates loop terminated")
+}
+
+// try to init netidx again, with backoff, until it works or the server
+// goes away
+fn retry_init(server: WeakServer) {
+    let mut backoff = INIT_RETRY_MIN;
+    loop {
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(INIT_RETRY_MAX);
+        let server = match server.upgrade() {
+            Some(server) => server,
+            None => break,
+        };
+        // the config may have been reloaded since the last attempt
+        let cfg = match &*server.inner.lock() {
+            State::Failed(failed) => failed.cfg.clone(),
+            State::Running(_) => break,
+        };
+        debug!("retrying netidx init");
+        match (server.init)(&cfg) {
+            Ok(ctx) => {
+                let inner = ServerInner::new(cfg, ctx, &server.shared);
+                server.init_succeeded(inner);
+                break;
+            }
+            Err(e) => {
+                warn!("could not init netidx {}, will retry in {:?}", e, backoff);
+                server.init_failed(e)
+            }
+        }
+    }
+}
+
+impl Server {
+    /// Start a server. If `netidx_cfg` isn't specified then the server
+    /// shares the process wide runtime and subscriber built from the
+    /// netidx config `cfg` asks for, otherwise it gets its own.
+    pub fn new(cfg: config::Config, netidx_cfg: Option<Config>) -> Server {
+        Self::with_init(
+            cfg,
+            Arc::new(move |cfg| match &netidx_cfg {
+                None => Context::shared(cfg),
+                Some(netidx_cfg) => {
+                    Context::new(cfg, Some(netidx_cfg.clone())).map(Arc::new)
+                }
+            }),
+        )
+    }
+
+    fn with_init(cfg: config::Config, init: Init) -> Server {
+        let shared = Arc::new(Shared::new(&cfg));
+        match init(&cfg) {
+            Ok(ctx) => {
+                let inner = ServerInner::new(cfg, ctx, &shared);
+                let inner = Arc::new(Mutex::new(State::Running(Box::new(inner))));
+                Server { inner, shared, init }
+            }
+            Err(e) => {
+                error!("could not init netidx {}, will retry", e);
+                let failed = Failed {
+                    cfg,
+                    error: e.to_string(),
+                    waiting: HashMap::with_hasher(FxBuildHasher::default()),
+                };
+                let inner = Arc::new(Mutex::new(State::Failed(failed)));
+                let server = Server { inner, shared, init };
+                let weak = server.downgrade();
+                // without retries the cells keep showing the init error,
+                // which is still better than taking Excel down
+                let retry = thread::Builder::new()
+                    .name("netidx-init".into())
+                    .spawn(move || retry_init(weak));
+                if let Err(e) = retry {
+                    error!("could not start the init retry thread {}", e)
+                }
+                server
+            }
+        }
+    }
+
+    fn downgrade(&self) -> WeakServer {
+        WeakServer {
+            inner: Arc::downgrade(&self.inner),
+            shared: Arc::downgrade(&self.shared),
+            init: self.init.clone(),
+        }
+    }
+
+    /// Apply a changed config. If it changes how netidx is initialized,
+    /// e.g. the auth mechanism, then netidx is initialized again and
+    /// every topic is resubscribed, the cells keep their values in the
+    /// meantime. Writes are not done again.
+    pub fn reload(&self, cfg: config::Config) {
+        debug!("reload");
+        self.shared.set_config_error(cfg.load_error.clone());
+        self.shared.pending.set_max_batch(cfg.max_refresh_batch);
+        match &mut *self.inner.lock() {
+            State::Failed(failed) => {
+                failed.cfg = cfg;
+                return;
+            }
+            State::Running(inner) if !inner.cfg.needs_reinit(&cfg) => {
+                inner.set_cfg(&self.shared, cfg);
+                return;
+            }
+            State::Running(_) => (),
+        }
+        // initializing netidx can take a while, e.g. kerberos, topics
+        // keep connecting to the current one in the meantime
+        let ctx = match (self.init)(&cfg) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                error!("could not apply the new config, keeping the current one {}", e);
+                return;
+            }
+        };
+        let mut state = self.inner.lock();
+        let inner = match &mut *state {
+            State::Running(inner) => inner,
+            // a running server never goes back to failed
+            State::Failed(_) => return,
+        };
+        info!("config changed, resubscribing {} topics", inner.ops.len());
+        let mut old =
+            mem::replace(&mut **inner, ServerInner::new(cfg, ctx, &self.shared));
+        old.close(&self.shared);
+        for (tid, op) in old.ops.drain() {
+            let res = match op {
+                Op::Write(..) => continue,
+                Op::Data(profile, path, options) => {
+                    let op = Op::Data(profile.clone(), path.clone(), options.clone());
+                    inner.ops.insert(tid, op);
+                    inner.connect_data(&self.shared, tid, &profile, path, options, true)
+                }
+                op => inner.run(self, tid, op),
             };
-            debug!("starting subscriber");
-            Ok(Subscriber::new(config, auth)?)
-        });
-        let subscriber = or_err!(subscriber, "could not init subscriber {}");
-        debug!("init updates channel");
-        let (tx, rx) = runtime.block_on(async { mpsc::channel(3) });
-        let t = Server(Arc::new(Mutex::new(Some(ServerInner {
-            runtime,
-            update: None,
-            subscriber,
-            updates: tx,
-            by_id: HashMap::with_hasher(FxBuildHasher::default()),
-            by_topic: HashMap::with_hasher(FxBuildHasher::default()),
-            pending: PENDING.take(),
-        }))));
-        let t_ = t.clone();
-        if let Some(inner) = &mut *t.0.lock() {
-            debug!("starting updates loop");
-            inner.runtime.spawn(t_.updates_loop(rx));
+            if let Err(e) = res {
+                error!("could not resubscribe topic {}", e)
+            }
+        }
+        // the old runtime might take a moment to shut down
+        drop(state);
+        drop(old)
+    }
+
+    fn init_succeeded(&self, mut inner: ServerInner) {
+        let mut state = self.inner.lock();
+        if let State::Failed(failed) = &mut *state {
+            info!("netidx initialized, connecting {} topics", failed.waiting.len());
+            for (tid, (slot, op)) in failed.waiting.drain() {
+                slot.close();
+                if let Err(e) = inner.run(self, tid, op) {
+                    error!("could not connect topic {}", e);
+                    let slot = Slot::new(tid, options::DEFAULT.clone());
+                    self.shared
+                        .pending
+                        .push_cell(&slot, cell_of_topic_error(Errors::Excel, &e));
+                    inner.slots.insert(tid, slot);
+                }
+            }
+            *state = State::Running(Box::new(inner));
+        }
+    }
+
+    fn init_failed(&self, e: anyhow::Error) {
+        if let State::Failed(failed) = &mut *self.inner.lock() {
+            failed.set_error(&self.shared, e.to_string())
         }
-        t
     }
 
-    pub(crate) fn server_start(&self, update: IRTDUpdateEventWrap) {
-        if let Some(inner) = &mut *self.0.lock() {
-            inner.clear();
-            inner.update = Some(update);
-            debug!("server_start");
+    pub fn server_start<N: Notify>(&self, update: N) {
+        match &mut *self.inner.lock() {
+            State::Running(inner) => inner.clear(&self.shared),
+            State::Failed(failed) => failed.clear(&self.shared),
         }
+        self.shared.clear_status_topics();
+        self.shared.pending.set_notify(Some(Box::new(update)));
+        debug!("server_start");
     }
 
-    pub(crate) fn server_terminate(&self) {
-        if let Some(inner) = &mut *self.0.lock() {
-            inner.clear();
-            debug!("server_terminate");
+    pub fn server_terminate(&self) {
+        match &mut *self.inner.lock() {
+            State::Running(inner) => inner.clear(&self.shared),
+            State::Failed(failed) => failed.clear(&self.shared),
         }
+        self.shared.clear_status_topics();
+        debug!("server_terminate");
     }
 
-    pub(crate) fn connect_data(&self, tid: TopicId, path: Path) -> Result<()> {
+    fn run(&self, tid: TopicId, op: Op) -> Result<()> {
+        match &mut *self.inner.lock() {
+            State::Running(inner) => inner.run(self, tid, op),
+            State::Failed(failed) => {
+                failed.wait(&self.shared, tid, op);
+                Ok(())
+            }
+        }
+    }
+
+    /// subscribe `tid` to `path`, in the netidx cluster of `profile` if
+    /// there is one
+    pub(crate) fn connect_data(
+        &self,
+        tid: TopicId,
+        profile: Option<String>,
+        path: Path,
+        options: Options,
+    ) -> Result<()> {
         debug!("connect_data");
-        if let Some(inner) = &mut *self.0.lock() {
-            let dv = inner.subscriber.subscribe(path);
-            inner.pending.insert(tid, dv.last());
-            if let Some(update) = inner.update.as_ref() {
-                update.update_notify()
-            }
-            dv.updates(UpdatesFlags::BEGIN_WITH_LAST, inner.updates.clone());
-            inner
-                .by_id
-                .entry(dv.id())
-                .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
-                .insert(tid);
-            inner.by_topic.insert(tid, dv);
+        self.run(tid, Op::Data(profile, path, options))
+    }
+
+    pub(crate) fn write(
+        &self,
+        tid: TopicId,
+        profile: Option<String>,
+        path: Path,
+        v: Value,
+    ) -> Result<()> {
+        debug!("write");
+        self.run(tid, Op::Write(profile, path, v))
+    }
+
+    fn write_result(&self, tid: TopicId, v: Value) {
+        debug!("write_result");
+        if let State::Running(inner) = &mut *self.inner.lock() {
+            if inner.writes.contains_key(&tid) {
+                inner.push(&self.shared, tid, Event::Update(v));
+            }
+        }
+    }
+
+    pub(crate) fn publish(&self, tid: TopicId, name: &str, v: Value) -> Result<()> {
+        debug!("publish");
+        self.run(tid, Op::Publish(name.into(), v))
+    }
+
+    pub(crate) fn browse(
+        &self,
+        tid: TopicId,
+        profile: Option<String>,
+        query: Query,
+        idx: Option<usize>,
+    ) -> Result<()> {
+        debug!("browse");
+        self.run(tid, Op::Browse(profile, query, idx))
+    }
+
+    /// show why the config file couldn't be loaded, or nothing if it was.
+    /// This works even if netidx can't be initialized.
+    pub(crate) fn config_status(&self, tid: TopicId) -> Result<()> {
+        debug!("config_status");
+        let mut status = self.shared.config_status.lock();
+        let slot = Slot::new(tid, options::DEFAULT.clone());
+        self.shared.pending.push(&slot, Event::Update(status.value()));
+        if let Some(old) = status.topics.insert(tid, slot) {
+            old.close()
+        }
+        Ok(())
+    }
+
+    fn publisher_ready(&self, token: Arc<()>, publisher: Result<Publisher, String>) {
+        debug!("publisher_ready");
+        if let State::Running(inner) = &mut *self.inner.lock() {
+            let runtime = inner.ctx.runtime();
+            for (tid, v) in inner.publish.ready(runtime, token, publisher) {
+                inner.push(&self.shared, tid, Event::Update(v));
+            }
+        }
+    }
+
+    /// show how often updates were blocked or dropped because the server
+    /// was behind. This works even if netidx can't be initialized.
+    pub(crate) fn counter(&self, tid: TopicId, counter: Counter) -> Result<()> {
+        debug!("counter");
+        let mut counters = self.shared.counters.lock();
+        let slot = Slot::new(tid, options::DEFAULT.clone());
+        let n = self.shared.counter(counter).load(Ordering::Relaxed);
+        self.shared.pending.push(&slot, Event::Update(Value::U64(n)));
+        if let Some((_, old)) = counters.insert(tid, (counter, slot)) {
+            old.close()
         }
         Ok(())
     }
 
-    pub(crate) fn disconnect_data(&self, tid: TopicId) {
+    // returns false if the query should not be asked again
+    fn browse_result(
+        &self,
+        token: &Arc<()>,
+        profile: Option<String>,
+        query: Query,
+        listing: Result<Vec<String>, String>,
+    ) -> bool {
+        debug!("browse_result");
+        match &mut *self.inner.lock() {
+            State::Failed(_) => false,
+            State::Running(inner) => {
+                match inner.browse.ready(token, profile, query, listing) {
+                    None => false,
+                    Some(ready) => {
+                        for (tid, v) in ready {
+                            inner.push(&self.shared, tid, Event::Update(v));
+                        }
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn disconnect_data(&self, tid: TopicId) {
         debug!("disconnect_data");
-        if let Some(inner) = &mut *self.0.lock() {
-            inner.pending.remove(&tid);
-            if let Some(dv) = inner.by_topic.remove(&tid) {
-                if let Some(tids) = inner.by_id.get_mut(&dv.id()) {
-                    tids.remove(&tid);
-                    if tids.is_empty() {
-                        inner.by_id.remove(&dv.id());
+        if let Some(slot) = self.shared.config_status.lock().topics.remove(&tid) {
+            slot.close()
+        }
+        if let Some((_, slot)) = self.shared.counters.lock().remove(&tid) {
+            slot.close()
+        }
+        match &mut *self.inner.lock() {
+            State::Failed(failed) => {
+                if let Some((slot, _)) = failed.waiting.remove(&tid) {
+                    slot.close()
+                }
+            }
+            State::Running(inner) => {
+                if let Some(slot) = inner.slots.remove(&tid) {
+                    slot.close()
+                }
+                inner.ops.remove(&tid);
+                inner.writes.remove(&tid);
+                inner.publish.unpublish(tid);
+                inner.browse.remove(tid);
+                if let Some(dv) = inner.by_topic.remove(&tid) {
+                    let mut by_id = self.shared.by_id.write();
+                    if let Some(sub) = by_id.get_mut(&dv.id()) {
+                        sub.slots.remove(&tid);
+                        if sub.slots.is_empty() {
+                            by_id.remove(&dv.id());
+                        }
                     }
                 }
             }
         }
     }
 
-    pub(crate) fn refresh_data(&self) -> Pooled<FxHashMap<TopicId, Event>> {
-        match &mut *self.0.lock() {
-            Some(inner) => {
-                debug!("refresh_data");
-                mem::replace(&mut inner.pending, PENDING.take())
+    /// take everything that changed since the last call, formatted for
+    /// the cells it will be shown in. This never waits for the network.
+    pub fn refresh_data(&self) -> Vec<(TopicId, Cell)> {
+        debug!("refresh_data");
+        self.shared.pending.take()
+    }
+
+    pub fn stats(&self) -> Stats {
+        match &*self.inner.lock() {
+            State::Failed(_) => {
+                Stats { pending: self.shared.pending.len(), ..Stats::default() }
             }
-            None => Pooled::orphan(HashMap::default()),
+            State::Running(inner) => Stats {
+                subscriptions: self.shared.by_id.read().len(),
+                topics: inner.by_topic.len(),
+                pending: self.shared.pending.len(),
+                blocked: self.shared.blocked.load(Ordering::Relaxed),
+                dropped: self.shared.dropped.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::ExcelError;
+    use anyhow::bail;
+    use std::{sync::mpsc, time::Instant};
+
+    #[test]
+    fn init_is_retried() {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let attempts_ = attempts.clone();
+        let server = Server::with_init(
+            config::Config::default(),
+            Arc::new(move |cfg| {
+                if attempts_.fetch_add(1, Ordering::Relaxed) == 0 {
+                    bail!("no kerberos ticket")
+                }
+                let netidx_cfg = Config::parse(
+                    r#"{ "base": "/", "addrs": [["127.0.0.1:4564", "Anonymous"]] }"#,
+                )?;
+                Ok(Arc::new(Context::new(cfg, Some(netidx_cfg))?))
+            }),
+        );
+        let (tx, rx) = mpsc::channel();
+        server.server_start(tx);
+        let message = Options { errors: Errors::Message, ..Options::default() };
+        let text = Options { errors: Errors::Text, ..Options::default() };
+        server
+            .connect_data(TopicId(0), None, Path::from("/a"), Options::default())
+            .unwrap();
+        server.connect_data(TopicId(1), None, Path::from("/b"), message).unwrap();
+        server
+            .connect_data(TopicId(2), None, Path::from("/c"), Options::default())
+            .unwrap();
+        server.disconnect_data(TopicId(2));
+        server.connect_data(TopicId(3), None, Path::from("/d"), text).unwrap();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            server.refresh_data(),
+            vec![
+                (TopicId(0), Cell::Error(ExcelError::NA)),
+                (TopicId(1), Cell::String(Chars::from("no kerberos ticket"))),
+                (TopicId(3), Cell::String(Chars::from("#INIT: no kerberos ticket"))),
+            ]
+        );
+        assert_eq!(server.stats().topics, 0);
+        // the topics that are still connected subscribe once init works
+        let start = Instant::now();
+        while server.stats().topics < 3 {
+            assert!(start.elapsed() < Duration::from_secs(10), "init was not retried");
+            thread::sleep(Duration::from_millis(10))
         }
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(server.stats().topics, 3);
+        server.server_terminate();
     }
 }
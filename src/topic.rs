@@ -0,0 +1,155 @@
+use crate::{
+    browse::Query,
+    cell::{cell_of_topic_error, Cell},
+    options::{parse_value, Errors, Options},
+    server::{Counter, Server, TopicId},
+};
+use anyhow::{anyhow, bail, Result};
+use log::error;
+use netidx::{
+    chars::Chars,
+    path::Path,
+    subscriber::{Typ, Value},
+};
+
+fn topic_string(v: &Value, what: &str) -> Result<String> {
+    match v {
+        Value::String(s) => Ok(String::from(&**s)),
+        v => bail!("expected {} to be a string, got {}", what, v),
+    }
+}
+
+fn next_topic<'a>(
+    topics: &mut impl Iterator<Item = &'a Value>,
+    what: &str,
+) -> Result<String> {
+    match topics.next() {
+        None => bail!("not enough topics, missing {}", what),
+        Some(v) => topic_string(v, what),
+    }
+}
+
+// a typo in a trailing topic must not go unnoticed, especially on a write
+// or publish
+fn no_more_topics<'a>(topics: &mut impl Iterator<Item = &'a Value>) -> Result<()> {
+    match topics.next() {
+        None => Ok(()),
+        Some(v) => bail!("too many topics, unexpected {}", v),
+    }
+}
+
+// without a type hint the value is parsed as a netidx value literal, and
+// if that fails it is taken as a string.
+fn value_of_topic(v: &Value, hint: Option<&Value>) -> Result<Value> {
+    match (v, hint) {
+        (Value::String(s), None) => Ok(parse_value(String::from(&**s))),
+        (v, None) => Ok(v.clone()),
+        (v, Some(typ)) => {
+            let typ = topic_string(typ, "type")?.to_lowercase().parse::<Typ>()?;
+            match v {
+                Value::String(s) => typ.parse(s),
+                v => {
+                    let err = anyhow!("can't cast {} to {:?}", v, typ);
+                    v.clone().cast(typ).ok_or(err)
+                }
+            }
+        }
+    }
+}
+
+// a path, or a glob, can start with the name of the profile it belongs
+// to, e.g. uat:/risk/pnl
+fn split_profile(s: String) -> (Option<String>, String) {
+    match s.split_once(':') {
+        Some((profile, path))
+            if !profile.is_empty() && !profile.contains('/') && path.starts_with('/') =>
+        {
+            (Some(profile.into()), String::from(path))
+        }
+        Some(_) | None => (None, s),
+    }
+}
+
+fn profile_and_path(s: String) -> (Option<String>, Path) {
+    let (profile, path) = split_profile(s);
+    (profile, Path::from(path))
+}
+
+fn connect_topic<'a>(
+    server: &Server,
+    tid: TopicId,
+    topics: &mut impl Iterator<Item = &'a Value>,
+) -> Result<()> {
+    let first = next_topic(topics, "path")?;
+    match first.as_str() {
+        "write" => {
+            let (profile, path) = profile_and_path(next_topic(topics, "path")?);
+            let value = match topics.next() {
+                None => bail!("not enough topics, missing value"),
+                Some(v) => value_of_topic(v, topics.next())?,
+            };
+            no_more_topics(topics)?;
+            server.write(tid, profile, path, value)
+        }
+        "publish" => {
+            let name = next_topic(topics, "name")?;
+            let value = match topics.next() {
+                None => bail!("not enough topics, missing value"),
+                Some(v) => value_of_topic(v, topics.next())?,
+            };
+            no_more_topics(topics)?;
+            server.publish(tid, &name, value)
+        }
+        "config" => server.config_status(tid),
+        "stats" => {
+            let counter = match next_topic(topics, "blocked or dropped")?.as_str() {
+                "blocked" => Counter::Blocked,
+                "dropped" => Counter::Dropped,
+                s => bail!("expected blocked or dropped, got {}", s),
+            };
+            server.counter(tid, counter)
+        }
+        "list" | "glob" | "table" => {
+            let (profile, arg) = split_profile(next_topic(topics, "path")?);
+            let query = match first.as_str() {
+                "list" => Query::List(Path::from(arg)),
+                "glob" => Query::Glob(Chars::from(arg)),
+                _ => match next_topic(topics, "rows or columns")?.as_str() {
+                    "rows" => Query::Rows(Path::from(arg)),
+                    "columns" => Query::Columns(Path::from(arg)),
+                    s => bail!("expected rows or columns, got {}", s),
+                },
+            };
+            let idx = match topics.next() {
+                None => None,
+                Some(v) => Some(topic_string(v, "index")?.trim().parse::<usize>()?),
+            };
+            server.browse(tid, profile, query, idx)
+        }
+        _ => {
+            let options =
+                topics.map(|v| topic_string(v, "option")).collect::<Result<Vec<_>>>()?;
+            let options = Options::parse(&options)?;
+            let (profile, path) = profile_and_path(first);
+            server.connect_data(tid, profile, path, options)
+        }
+    }
+}
+
+/// Connect `tid` to whatever the topic strings of an RTD call ask for,
+/// a path with options, or one of the write, publish, list, glob, table,
+/// config or stats commands. If the topic can't be connected then the cell
+/// that should be shown instead is returned.
+pub fn connect(server: &Server, tid: TopicId, topics: &[Value]) -> Result<(), Cell> {
+    connect_topic(server, tid, &mut topics.iter()).map_err(|e| {
+        error!("connect_data invalid topic {}", e);
+        // show the error the way the topic asked for, if it said
+        let errors = topics
+            .iter()
+            .filter_map(|v| topic_string(v, "option").ok())
+            .filter_map(|s| s.strip_prefix("errors=").and_then(|e| e.parse().ok()))
+            .last()
+            .unwrap_or(Errors::Excel);
+        cell_of_topic_error(errors, &e)
+    })
+}
@@ -0,0 +1,223 @@
+//! A local netidx resolver and publisher, and a fake RTD host that drives
+//! the server the same way Excel does.
+#![allow(dead_code)]
+use futures::{channel::mpsc as fmpsc, prelude::*};
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    publisher::{Publisher, PublisherBuilder, Val, WriteRequest},
+    resolver_server::{config::Config as ResolverConfig, Server as Resolver},
+    subscriber::{DesiredAuth, Value},
+};
+use netidx_excel::{
+    cell::Cell,
+    config,
+    server::{Server, TopicId},
+    topic,
+};
+use std::{
+    collections::HashMap,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+const RESOLVER: &str = r#"{
+    "parent": null,
+    "children": [],
+    "member_servers": [{
+        "pid_file": "",
+        "addr": "127.0.0.1:0",
+        "max_connections": 768,
+        "hello_timeout": 10,
+        "reader_ttl": 60,
+        "writer_ttl": 120,
+        "auth": "Anonymous"
+    }],
+    "perms": {}
+}"#;
+
+const CLIENT: &str = r#"{
+    "base": "/",
+    "addrs": [["127.0.0.1:4564", "Anonymous"]]
+}"#;
+
+/// A resolver and publisher on loopback, running in their own runtime
+pub struct Netidx {
+    // fields drop in order, the runtime must outlive the rest
+    pub publisher: Option<Publisher>,
+    pub resolver: Option<Resolver>,
+    pub config: Config,
+    pub runtime: Runtime,
+}
+
+impl Netidx {
+    pub fn start() -> Self {
+        let runtime = Runtime::new().unwrap();
+        let (config, resolver) = runtime.block_on(async {
+            let cfg = ResolverConfig::parse(RESOLVER).unwrap();
+            let resolver = Resolver::new(cfg, false, 0).await.unwrap();
+            let mut config = Config::parse(CLIENT).unwrap();
+            config.addrs[0].0 = *resolver.local_addr();
+            (config, resolver)
+        });
+        let mut t = Netidx { publisher: None, resolver: Some(resolver), config, runtime };
+        t.start_publisher();
+        t
+    }
+
+    pub fn start_publisher(&mut self) {
+        let config = self.config.clone();
+        let publisher = self.runtime.block_on(async {
+            let mut builder = PublisherBuilder::new(config);
+            builder.desired_auth(DesiredAuth::Anonymous);
+            builder.bind_cfg(Some("127.0.0.1/32".parse().unwrap()));
+            builder.build().await.unwrap()
+        });
+        self.publisher = Some(publisher);
+    }
+
+    /// kill the publisher, all its values go away with it
+    pub fn stop_publisher(&mut self) {
+        if let Some(publisher) = self.publisher.take() {
+            self.runtime.block_on(publisher.shutdown());
+        }
+    }
+
+    pub fn publisher(&self) -> &Publisher {
+        self.publisher.as_ref().expect("publisher is not running")
+    }
+
+    pub fn publish(&self, path: &str, v: Value) -> Val {
+        let val = self.publisher().publish(Path::from(String::from(path)), v).unwrap();
+        self.runtime.block_on(self.publisher().flushed());
+        val
+    }
+
+    pub fn update(&self, val: &Val, v: Value) {
+        let mut batch = self.publisher().start_batch();
+        val.update(&mut batch, v);
+        self.runtime.block_on(batch.commit(None))
+    }
+
+    /// accept writes to `val`, they arrive on the returned channel
+    pub fn writes(&self, val: &Val) -> mpsc::Receiver<WriteRequest> {
+        let (tx, mut rx) = fmpsc::channel(10);
+        self.publisher().writes(val.id(), tx);
+        let (fwd, writes) = mpsc::channel();
+        self.runtime.spawn(async move {
+            while let Some(mut batch) = rx.next().await {
+                for req in batch.drain(..) {
+                    if fwd.send(req).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        writes
+    }
+}
+
+/// Plays the part of Excel. Each topic is a cell, `refresh` waits for
+/// UpdateNotify and then calls RefreshData, just like the real thing.
+pub struct Host {
+    pub server: Server,
+    notified: mpsc::Receiver<()>,
+    next_tid: i32,
+    pub cells: HashMap<TopicId, Cell>,
+    /// the size of every RefreshData batch
+    pub batches: Vec<usize>,
+    /// how long every RefreshData call took
+    pub latencies: Vec<Duration>,
+}
+
+impl Host {
+    pub fn new(netidx: &Netidx) -> Self {
+        Self::with_config(netidx, config::Config::default())
+    }
+
+    pub fn with_config(netidx: &Netidx, cfg: config::Config) -> Self {
+        let server = Server::new(cfg, Some(netidx.config.clone()));
+        let (tx, notified) = mpsc::channel();
+        server.server_start(tx);
+        Host {
+            server,
+            notified,
+            next_tid: 0,
+            cells: HashMap::new(),
+            batches: vec![],
+            latencies: vec![],
+        }
+    }
+
+    /// ConnectData, returns the topic id
+    pub fn connect(&mut self, topics: &[&str]) -> TopicId {
+        let tid = TopicId(self.next_tid);
+        self.next_tid += 1;
+        let topics = topics
+            .iter()
+            .map(|s| Value::String(Chars::from(String::from(*s))))
+            .collect::<Vec<_>>();
+        if let Err(cell) = topic::connect(&self.server, tid, &topics) {
+            self.cells.insert(tid, cell);
+        }
+        tid
+    }
+
+    /// DisconnectData
+    pub fn disconnect(&mut self, tid: TopicId) {
+        self.server.disconnect_data(tid);
+        self.cells.remove(&tid);
+    }
+
+    /// wait for UpdateNotify, then RefreshData. Returns the batch.
+    pub fn refresh(&mut self) -> Vec<(TopicId, Cell)> {
+        self.refresh_within(TIMEOUT)
+    }
+
+    pub fn refresh_within(&mut self, timeout: Duration) -> Vec<(TopicId, Cell)> {
+        self.notified.recv_timeout(timeout).expect("no update notification");
+        while self.notified.try_recv().is_ok() {}
+        let start = Instant::now();
+        let batch = self.server.refresh_data();
+        self.latencies.push(start.elapsed());
+        self.batches.push(batch.len());
+        for (tid, cell) in &batch {
+            self.cells.insert(*tid, cell.clone());
+        }
+        batch
+    }
+
+    pub fn cell(&self, tid: TopicId) -> Option<&Cell> {
+        self.cells.get(&tid)
+    }
+
+    /// keep refreshing until `tid` shows `expected`
+    pub fn wait_for(&mut self, tid: TopicId, expected: &Cell) {
+        self.wait_for_within(tid, expected, TIMEOUT)
+    }
+
+    pub fn wait_for_within(&mut self, tid: TopicId, expected: &Cell, timeout: Duration) {
+        let start = Instant::now();
+        while self.cell(tid) != Some(expected) {
+            let elapsed = start.elapsed();
+            if elapsed > timeout {
+                panic!("{:?} is {:?}, expected {:?}", tid, self.cell(tid), expected)
+            }
+            self.refresh_within(timeout - elapsed);
+        }
+    }
+
+    /// ServerTerminate
+    pub fn terminate(self) -> Server {
+        self.server.server_terminate();
+        self.server
+    }
+}
+
+pub fn string(s: &str) -> Cell {
+    Cell::String(Chars::from(String::from(s)))
+}
@@ -0,0 +1,291 @@
+mod common;
+
+use common::{string, Host, Netidx, TIMEOUT};
+use netidx::{chars::Chars, subscriber::Value};
+use netidx_excel::{
+    cell::{Cell, ExcelError},
+    config,
+};
+use std::time::{Duration, Instant};
+
+// the subscriber backs off between attempts to resubscribe, so it can take
+// a while for a path to come back
+const RESUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// how long the server waits for a write to be acknowledged
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+fn initial_values() {
+    let netidx = Netidx::start();
+    let _vals = [
+        netidx.publish("/local/i32", Value::I32(-7)),
+        netidx.publish("/local/u64", Value::U64(7)),
+        netidx.publish("/local/f64", Value::F64(1.5)),
+        netidx.publish("/local/bool", Value::True),
+        netidx.publish("/local/string", Value::String(Chars::from("hello"))),
+        netidx.publish("/local/null", Value::Null),
+    ];
+    let mut host = Host::new(&netidx);
+    for (path, expected) in [
+        ("/local/i32", Cell::I32(-7)),
+        ("/local/u64", Cell::U64(7)),
+        ("/local/f64", Cell::F64(1.5)),
+        ("/local/bool", Cell::Bool(true)),
+        ("/local/string", string("hello")),
+        ("/local/null", Cell::Null),
+    ] {
+        let tid = host.connect(&[path]);
+        host.wait_for(tid, &expected);
+    }
+    host.terminate();
+}
+
+#[test]
+fn live_updates() {
+    let netidx = Netidx::start();
+    let val = netidx.publish("/local/live", Value::I64(0));
+    let mut host = Host::new(&netidx);
+    let tid = host.connect(&["/local/live"]);
+    host.wait_for(tid, &Cell::I64(0));
+    for i in 1..10 {
+        netidx.update(&val, Value::I64(i));
+        host.wait_for(tid, &Cell::I64(i));
+    }
+    netidx.update(&val, Value::Error(Chars::from("broken")));
+    host.wait_for(tid, &Cell::Error(ExcelError::Value));
+    host.terminate();
+}
+
+#[test]
+fn subscribe_before_publish() {
+    let netidx = Netidx::start();
+    let mut host = Host::new(&netidx);
+    let tid = host.connect(&["/local/later"]);
+    host.wait_for(tid, &Cell::Error(ExcelError::NA));
+    let _val = netidx.publish("/local/later", Value::I64(42));
+    host.wait_for_within(tid, &Cell::I64(42), RESUBSCRIBE_TIMEOUT);
+    host.terminate();
+}
+
+#[test]
+fn publisher_death_and_resubscription() {
+    let mut netidx = Netidx::start();
+    let val = netidx.publish("/local/mortal", Value::I64(1));
+    let mut host = Host::new(&netidx);
+    let excel = host.connect(&["/local/mortal"]);
+    let text = host.connect(&["/local/mortal", "errors=text"]);
+    let default = host.connect(&["/local/mortal", "default=0"]);
+    host.wait_for(excel, &Cell::I64(1));
+    host.wait_for(text, &Cell::I64(1));
+    host.wait_for(default, &Cell::I64(1));
+    drop(val);
+    netidx.stop_publisher();
+    host.wait_for(excel, &Cell::Error(ExcelError::NA));
+    host.wait_for(text, &string("#SUB"));
+    host.wait_for(default, &Cell::I64(0));
+    netidx.start_publisher();
+    let _val = netidx.publish("/local/mortal", Value::I64(2));
+    host.wait_for_within(excel, &Cell::I64(2), RESUBSCRIBE_TIMEOUT);
+    host.wait_for_within(text, &Cell::I64(2), RESUBSCRIBE_TIMEOUT);
+    host.wait_for_within(default, &Cell::I64(2), RESUBSCRIBE_TIMEOUT);
+    // still one subscription, shared by all three cells
+    assert_eq!(host.server.stats().subscriptions, 1);
+    host.terminate();
+}
+
+#[test]
+fn writes() {
+    let mut netidx = Netidx::start();
+    let val = netidx.publish("/local/setpoint", Value::I64(0));
+    let writes = netidx.writes(&val);
+    let mut host = Host::new(&netidx);
+    let tid = host.connect(&["write", "/local/setpoint", "42"]);
+    let req = writes.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(req.value, Value::I64(42));
+    req.send_result.unwrap().send(Value::String(Chars::from("accepted")));
+    host.wait_for(tid, &string("accepted"));
+    // the publisher goes away before it replies, which is reported long
+    // before the write would time out
+    let failed = host.connect(&["write", "/local/setpoint", "43"]);
+    let req = writes.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(req.value, Value::I64(43));
+    netidx.stop_publisher();
+    drop(req);
+    host.wait_for_within(failed, &Cell::Error(ExcelError::Value), WRITE_TIMEOUT / 2);
+    host.terminate();
+}
+
+#[test]
+fn write_timeout() {
+    let netidx = Netidx::start();
+    let val = netidx.publish("/local/slow", Value::I64(0));
+    let writes = netidx.writes(&val);
+    let mut host = Host::new(&netidx);
+    let start = Instant::now();
+    let tid = host.connect(&["write", "/local/slow", "1"]);
+    // never answered
+    let _req = writes.recv_timeout(TIMEOUT).unwrap();
+    host.wait_for_within(tid, &Cell::Error(ExcelError::Value), WRITE_TIMEOUT * 2);
+    assert!(start.elapsed() >= WRITE_TIMEOUT);
+    host.terminate();
+}
+
+#[test]
+fn writes_are_not_repeated_on_reload() {
+    let netidx = Netidx::start();
+    let val = netidx.publish("/local/limit", Value::I64(0));
+    let writes = netidx.writes(&val);
+    let mut host = Host::new(&netidx);
+    let data = host.connect(&["/local/limit"]);
+    host.wait_for(data, &Cell::I64(0));
+    let tid = host.connect(&["write", "/local/limit", "7"]);
+    let req = writes.recv_timeout(TIMEOUT).unwrap();
+    netidx.update(&val, req.value.clone());
+    req.send_result.unwrap().send(Value::True);
+    host.wait_for(tid, &Cell::Bool(true));
+    host.wait_for(data, &Cell::I64(7));
+    let cfg = config::Config {
+        auth_mechanism: Some(config::Auth::Anonymous),
+        ..config::Config::default()
+    };
+    host.server.reload(cfg);
+    // once the data topic is resubscribed every topic has been redone
+    netidx.update(&val, Value::I64(8));
+    host.wait_for_within(data, &Cell::I64(8), RESUBSCRIBE_TIMEOUT);
+    assert!(writes.try_recv().is_err(), "the write was done again");
+    host.terminate();
+}
+
+#[test]
+fn reload_resubscribes() {
+    let netidx = Netidx::start();
+    let val = netidx.publish("/local/reloaded", Value::I64(1));
+    let mut host = Host::new(&netidx);
+    let tid = host.connect(&["/local/reloaded"]);
+    let status = host.connect(&["config"]);
+    host.wait_for(tid, &Cell::I64(1));
+    host.wait_for(status, &Cell::Null);
+    // a different auth mechanism means a new subscriber
+    let cfg = config::Config {
+        auth_mechanism: Some(config::Auth::Anonymous),
+        load_error: Some("config.json: expected value at line 1 column 1".into()),
+        ..config::Config::default()
+    };
+    host.server.reload(cfg);
+    host.wait_for(status, &string("config.json: expected value at line 1 column 1"));
+    netidx.update(&val, Value::I64(2));
+    // the cell never shows the gap between the two subscriptions
+    while host.cell(tid) != Some(&Cell::I64(2)) {
+        for (t, cell) in host.refresh_within(RESUBSCRIBE_TIMEOUT) {
+            assert!(t != tid || cell != Cell::Error(ExcelError::NA), "saw #N/A");
+        }
+    }
+    assert_eq!(host.server.stats().subscriptions, 1);
+    assert_eq!(host.server.stats().topics, 1);
+    host.terminate();
+}
+
+#[test]
+fn profiles() {
+    let prod = Netidx::start();
+    let uat = Netidx::start();
+    let _vals = [
+        prod.publish("/local/pnl", Value::I64(1)),
+        uat.publish("/local/pnl", Value::I64(2)),
+        uat.publish("/local/risk", Value::I64(3)),
+    ];
+    let resolver = serde_json::json!({
+        "base": "/",
+        "addrs": [[uat.config.addrs[0].0.to_string(), "Anonymous"]]
+    });
+    let profile =
+        config::Profile { resolver: Some(resolver), ..config::Profile::default() };
+    let cfg = config::Config {
+        profiles: [(String::from("uat"), profile)].into_iter().collect(),
+        ..config::Config::default()
+    };
+    let mut host = Host::with_config(&prod, cfg);
+    let prod_pnl = host.connect(&["/local/pnl"]);
+    let uat_pnl = host.connect(&["uat:/local/pnl"]);
+    let unknown = host.connect(&["dev:/local/pnl"]);
+    host.wait_for(prod_pnl, &Cell::I64(1));
+    host.wait_for(uat_pnl, &Cell::I64(2));
+    assert_eq!(host.cell(unknown), Some(&Cell::Error(ExcelError::Ref)));
+    // browsing asks the profile's resolver
+    let prod_list = host.connect(&["list", "/local"]);
+    let uat_list = host.connect(&["list", "uat:/local"]);
+    let uat_glob = host.connect(&["glob", "uat:/local/r*", "0"]);
+    let unknown_list = host.connect(&["list", "dev:/local"]);
+    host.wait_for(prod_list, &Cell::I64(1));
+    host.wait_for(uat_list, &Cell::I64(2));
+    host.wait_for(uat_glob, &string("/local/risk"));
+    assert_eq!(host.cell(unknown_list), Some(&Cell::Error(ExcelError::Ref)));
+    assert_eq!(host.server.stats().subscriptions, 2);
+    host.terminate();
+}
+
+#[test]
+fn publish_and_unpublish() {
+    let netidx = Netidx::start();
+    let cfg = config::Config {
+        publish_base: Some("/local/excel".into()),
+        publish_bind: Some("127.0.0.1/32".into()),
+        ..config::Config::default()
+    };
+    let mut host = Host::with_config(&netidx, cfg);
+    let extra = host.connect(&["publish", "mark", "1", "i64", "oops"]);
+    assert_eq!(host.cell(extra), Some(&Cell::Error(ExcelError::Ref)));
+    let a = host.connect(&["publish", "mark", "42"]);
+    let b = host.connect(&["publish", "mark", "42"]);
+    host.wait_for(a, &Cell::I64(42));
+    host.wait_for(b, &Cell::I64(42));
+    let mark = host.connect(&["/local/excel/mark"]);
+    host.wait_for(mark, &Cell::I64(42));
+    // the path stays published until the last topic publishing it is gone
+    host.disconnect(a);
+    let c = host.connect(&["publish", "mark", "43"]);
+    host.wait_for(mark, &Cell::I64(43));
+    host.disconnect(b);
+    host.wait_for(c, &Cell::I64(43));
+    host.disconnect(c);
+    host.wait_for(mark, &Cell::Error(ExcelError::NA));
+    host.terminate();
+}
+
+#[test]
+fn browse() {
+    let netidx = Netidx::start();
+    let _vals =
+        ["a", "b", "c/x", "table/r0/c0", "table/r0/c1", "table/r1/c0", "table/r1/c1"]
+            .iter()
+            .map(|p| netidx.publish(&format!("/local/browse/{}", p), Value::I64(0)))
+            .collect::<Vec<_>>();
+    let mut host = Host::new(&netidx);
+    let count = host.connect(&["list", "/local/browse"]);
+    let first = host.connect(&["list", "/local/browse", "0"]);
+    let missing = host.connect(&["list", "/local/browse", "10"]);
+    let glob = host.connect(&["glob", "/local/browse/table/*/c1", "1"]);
+    let rows = host.connect(&["table", "/local/browse/table", "rows"]);
+    let row = host.connect(&["table", "/local/browse/table", "rows", "1"]);
+    let column = host.connect(&["table", "/local/browse/table", "columns", "0"]);
+    host.wait_for(count, &Cell::I64(4));
+    host.wait_for(first, &string("/local/browse/a"));
+    host.wait_for(missing, &Cell::Null);
+    host.wait_for(glob, &string("/local/browse/table/r1/c1"));
+    host.wait_for(rows, &Cell::I64(2));
+    host.wait_for(row, &string("r1"));
+    host.wait_for(column, &string("c0"));
+    // a query that was already answered is answered from the cache
+    let last = host.connect(&["list", "/local/browse", "3"]);
+    host.wait_for(last, &string("/local/browse/table"));
+    host.disconnect(count);
+    host.disconnect(first);
+    host.disconnect(missing);
+    let again = host.connect(&["list", "/local/browse"]);
+    host.wait_for(again, &Cell::I64(4));
+    // queries are asked again, so new paths show up
+    let _d = netidx.publish("/local/browse/d", Value::I64(0));
+    host.wait_for_within(again, &Cell::I64(5), Duration::from_secs(30));
+    host.terminate();
+}
@@ -0,0 +1,283 @@
+mod common;
+
+use chrono::prelude::*;
+use common::{string, Host, Netidx};
+use netidx::{chars::Chars, subscriber::Value};
+use netidx_excel::{
+    cell::{ole_date_of_datetime, Cell, ExcelError},
+    config,
+    options::DateTimeFormat,
+    server::Stats,
+};
+use std::{collections::HashSet, num::NonZeroUsize, sync::Arc};
+
+// values shown as text look the way netidx prints them
+fn shown(v: Value) -> Cell {
+    string(&v.to_string())
+}
+
+fn array(a: &[i64]) -> Value {
+    Value::Array(Arc::from(a.iter().map(|i| Value::I64(*i)).collect::<Vec<_>>()))
+}
+
+#[test]
+fn values() {
+    let netidx = Netidx::start();
+    let _a = netidx.publish("/local/a", Value::I64(42));
+    let _s = netidx.publish("/local/s", Value::String(Chars::from("hello")));
+    let _f = netidx.publish("/local/f", Value::F64(0.5));
+    let mut host = Host::new(&netidx);
+    let a = host.connect(&["/local/a"]);
+    let s = host.connect(&["/local/s"]);
+    let f = host.connect(&["/local/f", "scale=100"]);
+    let t = host.connect(&["/local/a", "format=text"]);
+    host.wait_for(a, &Cell::I64(42));
+    host.wait_for(s, &string("hello"));
+    host.wait_for(f, &Cell::F64(50.));
+    host.wait_for(t, &shown(Value::I64(42)));
+    host.terminate();
+}
+
+#[test]
+fn formats_from_the_servers_config() {
+    let netidx = Netidx::start();
+    let d = Utc.with_ymd_and_hms(2022, 3, 1, 12, 0, 0).unwrap();
+    let _d = netidx.publish("/local/when", Value::DateTime(d));
+    let cfg =
+        config::Config { datetime: DateTimeFormat::Text, ..config::Config::default() };
+    let mut host = Host::with_config(&netidx, cfg);
+    let text = host.connect(&["/local/when"]);
+    let utc = host.connect(&["/local/when", "datetime=utc"]);
+    host.wait_for(text, &string("2022-03-01 12:00:00 UTC"));
+    host.wait_for(utc, &Cell::Date(ole_date_of_datetime(d.naive_utc())));
+    // cells that didn't ask for a format follow the config
+    host.server.reload(config::Config::default());
+    host.wait_for(text, &Cell::Date(ole_date_of_datetime(d.naive_utc())));
+    host.terminate();
+}
+
+#[test]
+fn updates_are_coalesced() {
+    let netidx = Netidx::start();
+    let val = netidx.publish("/local/counter", Value::I64(0));
+    let mut host = Host::new(&netidx);
+    let c = host.connect(&["/local/counter"]);
+    let d = host.connect(&["/local/counter", "scale=2"]);
+    host.wait_for(c, &Cell::I64(0));
+    host.wait_for(d, &Cell::F64(0.));
+    host.batches.clear();
+    // Excel is busy while these arrive, so they pile up in pending and it
+    // only ever sees one value per topic in each refresh
+    for i in 1..=1000 {
+        netidx.update(&val, Value::I64(i));
+    }
+    let mut delivered = 0;
+    while host.cell(c) != Some(&Cell::I64(1000))
+        || host.cell(d) != Some(&Cell::F64(2000.))
+    {
+        let batch = host.refresh();
+        let tids = batch.iter().map(|(tid, _)| *tid).collect::<HashSet<_>>();
+        assert_eq!(tids.len(), batch.len(), "a topic appeared twice in one batch");
+        delivered += batch.len();
+    }
+    assert!(delivered < 2000, "{} updates were delivered, nothing coalesced", delivered);
+    assert!(host.batches.iter().all(|n| *n <= 2));
+    host.terminate();
+}
+
+#[test]
+fn refresh_batches_are_capped() {
+    let netidx = Netidx::start();
+    let vals = (0..100)
+        .map(|i| netidx.publish(&format!("/local/capped/{}", i), Value::I64(i)))
+        .collect::<Vec<_>>();
+    let cfg = config::Config {
+        max_refresh_batch: NonZeroUsize::new(10),
+        ..config::Config::default()
+    };
+    let mut host = Host::with_config(&netidx, cfg);
+    let tids = (0..100)
+        .map(|i| host.connect(&[&format!("/local/capped/{}", i)]))
+        .collect::<Vec<_>>();
+    for (i, tid) in tids.iter().enumerate() {
+        host.wait_for(*tid, &Cell::I64(i as i64));
+    }
+    for (i, val) in vals.iter().enumerate() {
+        netidx.update(val, Value::I64(i as i64 + 100));
+    }
+    // nothing is lost, the leftovers of each batch ask for another refresh
+    for (i, tid) in tids.iter().enumerate() {
+        host.wait_for(*tid, &Cell::I64(i as i64 + 100));
+    }
+    assert!(host.batches.iter().all(|n| *n <= 10));
+    assert!(host.batches.len() >= 20);
+    host.terminate();
+}
+
+#[test]
+fn dropped_updates_are_reread() {
+    let netidx = Netidx::start();
+    let vals = (0..100)
+        .map(|i| netidx.publish(&format!("/local/dropped/{}", i), Value::I64(0)))
+        .collect::<Vec<_>>();
+    let cfg = config::Config {
+        update_channel_depth: Some(0),
+        update_overflow: config::Overflow::Drop,
+        ..config::Config::default()
+    };
+    let mut host = Host::with_config(&netidx, cfg);
+    let dropped = host.connect(&["stats", "dropped"]);
+    let blocked = host.connect(&["stats", "blocked"]);
+    let tids = (0..100)
+        .map(|i| host.connect(&[&format!("/local/dropped/{}", i)]))
+        .collect::<Vec<_>>();
+    for tid in &tids {
+        host.wait_for(*tid, &Cell::I64(0));
+    }
+    for i in 1..=100 {
+        for val in &vals {
+            netidx.update(val, Value::I64(i));
+        }
+    }
+    // whether or not anything was dropped every cell ends up current
+    for tid in &tids {
+        host.wait_for(*tid, &Cell::I64(100));
+    }
+    assert_eq!(host.server.stats().blocked, 0);
+    host.wait_for(blocked, &Cell::U64(0));
+    let n = host.server.stats().dropped;
+    host.wait_for(dropped, &Cell::U64(n));
+    host.terminate();
+}
+
+#[test]
+fn new_topics_are_not_counted() {
+    let netidx = Netidx::start();
+    let val = netidx.publish("/local/ticks", Value::I64(0));
+    let mut host = Host::new(&netidx);
+    let count = host.connect(&["/local/ticks", "conflate=count"]);
+    let list = host.connect(&["/local/ticks", "conflate=list"]);
+    host.wait_for(count, &Cell::U64(1));
+    host.wait_for(list, &shown(array(&[0])));
+    // connecting another topic to the path must not look like an update
+    // to the topics already showing it
+    let latest = host.connect(&["/local/ticks"]);
+    let mut seen = vec![];
+    let mut updated = false;
+    while host.cell(latest) != Some(&Cell::I64(1)) || seen.len() < 2 {
+        for (tid, cell) in host.refresh() {
+            if tid != latest {
+                seen.push((tid, cell))
+            }
+        }
+        if !updated && host.cell(latest) == Some(&Cell::I64(0)) {
+            netidx.update(&val, Value::I64(1));
+            updated = true;
+        }
+    }
+    assert_eq!(seen.len(), 2, "{:?}", seen);
+    assert!(seen.contains(&(count, Cell::U64(1))), "{:?}", seen);
+    assert!(seen.contains(&(list, shown(array(&[1])))), "{:?}", seen);
+    host.terminate();
+}
+
+#[test]
+fn fan_out_shares_one_subscription() {
+    let netidx = Netidx::start();
+    let val = netidx.publish("/local/array", array(&[1, 2, 3]));
+    let mut host = Host::new(&netidx);
+    let whole = host.connect(&["/local/array"]);
+    let elts = (0..3)
+        .map(|i| host.connect(&["/local/array", &format!("[{}]", i)]))
+        .collect::<Vec<_>>();
+    let missing = host.connect(&["/local/array", "[3]"]);
+    for (i, tid) in elts.iter().enumerate() {
+        host.wait_for(*tid, &Cell::I64(i as i64 + 1));
+    }
+    host.wait_for(whole, &shown(array(&[1, 2, 3])));
+    host.wait_for(missing, &Cell::Error(ExcelError::Value));
+    assert_eq!(host.server.stats().subscriptions, 1);
+    assert_eq!(host.server.stats().topics, 5);
+    netidx.update(&val, array(&[4, 5, 6]));
+    for (i, tid) in elts.iter().enumerate() {
+        host.wait_for(*tid, &Cell::I64(i as i64 + 4));
+    }
+    for tid in elts {
+        host.disconnect(tid);
+    }
+    assert_eq!(host.server.stats().subscriptions, 1);
+    assert_eq!(host.server.stats().topics, 2);
+    host.disconnect(whole);
+    host.disconnect(missing);
+    assert_eq!(host.server.stats(), Stats::default());
+    host.terminate();
+}
+
+#[test]
+fn many_paths_and_cleanup() {
+    let netidx = Netidx::start();
+    let vals = (0..100)
+        .map(|i| netidx.publish(&format!("/local/many/{}", i), Value::I64(i)))
+        .collect::<Vec<_>>();
+    let mut host = Host::new(&netidx);
+    let tids = (0..100)
+        .map(|i| host.connect(&[&format!("/local/many/{}", i)]))
+        .collect::<Vec<_>>();
+    for (i, tid) in tids.iter().enumerate() {
+        host.wait_for(*tid, &Cell::I64(i as i64));
+    }
+    assert_eq!(host.server.stats().subscriptions, 100);
+    assert_eq!(host.server.stats().topics, 100);
+    // disconnected topics must not be delivered
+    for tid in &tids[50..] {
+        host.disconnect(*tid);
+    }
+    for (i, val) in vals.iter().enumerate() {
+        netidx.update(val, Value::I64(i as i64 + 1000));
+    }
+    for (i, tid) in tids[..50].iter().enumerate() {
+        host.wait_for(*tid, &Cell::I64(i as i64 + 1000));
+    }
+    assert!(host.server.refresh_data().iter().all(|(tid, _)| tid.0 < 50));
+    let stats = host.server.stats();
+    assert_eq!(stats.subscriptions, 50);
+    assert_eq!(stats.topics, 50);
+    let server = host.terminate();
+    assert_eq!(server.stats(), Stats::default());
+}
+
+#[test]
+fn bad_topics() {
+    let netidx = Netidx::start();
+    let mut host = Host::new(&netidx);
+    let missing = host.connect(&["write", "/local/a"]);
+    assert_eq!(host.cell(missing), Some(&Cell::Error(ExcelError::Ref)));
+    let extra = host.connect(&["write", "/local/a", "1", "i64", "oops"]);
+    assert_eq!(host.cell(extra), Some(&Cell::Error(ExcelError::Ref)));
+    let bad = host.connect(&["/local/a", "frobnicate=1", "errors=text"]);
+    assert_eq!(host.cell(bad), Some(&string("#ERR unknown option frobnicate")));
+    let blank = host.connect(&["/local/a", "scale=x", "errors=blank"]);
+    assert_eq!(host.cell(blank), Some(&Cell::Null));
+    let stats = host.connect(&["stats", "late"]);
+    assert_eq!(host.cell(stats), Some(&Cell::Error(ExcelError::Ref)));
+    assert_eq!(host.server.stats(), Stats::default());
+    host.terminate();
+}
+
+#[test]
+fn config_status() {
+    let netidx = Netidx::start();
+    let mut host = Host::new(&netidx);
+    let ok = host.connect(&["config"]);
+    host.wait_for(ok, &Cell::Null);
+    host.terminate();
+    let error = "config.json: unknown field `datetme` at line 3 column 11";
+    let cfg =
+        config::Config { load_error: Some(error.into()), ..config::Config::default() };
+    let mut host = Host::with_config(&netidx, cfg);
+    let status = host.connect(&["config"]);
+    host.wait_for(status, &string(error));
+    host.disconnect(status);
+    assert_eq!(host.server.stats(), Stats::default());
+    host.terminate();
+}